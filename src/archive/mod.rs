@@ -11,6 +11,8 @@ use tokio::fs;
 
 mod sink;
 
+static NAR_MAGIC: &str = "nix-archive-1";
+
 // if this is a type alias, it causes a compilation failure lol
 pub struct PathFilter(Option<Box<dyn Fn(&Path) -> bool + Send + Sync>>);
 
@@ -18,6 +20,10 @@ impl PathFilter {
   pub fn always() -> Self {
     Self(None)
   }
+
+  pub fn new<F: Fn(&Path) -> bool + Send + Sync + 'static>(f: F) -> Self {
+    Self(Some(Box::new(f)))
+  }
 }
 
 impl FnOnce<(&Path,)> for PathFilter {
@@ -106,7 +112,6 @@ where
   }
 }
 
-#[async_recursion]
 pub async fn dump_path<W: Sink<Bytes> + Send + Unpin>(
   path: &Path,
   sink: &mut ArchiveSink<W>,
@@ -115,7 +120,20 @@ pub async fn dump_path<W: Sink<Bytes> + Send + Unpin>(
 where
   W::Error: Error + Send + Sync + 'static,
 {
-  let meta = fs::metadata(path).await?;
+  sink.write_str(NAR_MAGIC).await?;
+  dump(path, sink, filter).await
+}
+
+#[async_recursion]
+async fn dump<W: Sink<Bytes> + Send + Unpin>(
+  path: &Path,
+  sink: &mut ArchiveSink<W>,
+  filter: &PathFilter,
+) -> Result<()>
+where
+  W::Error: Error + Send + Sync + 'static,
+{
+  let meta = fs::symlink_metadata(path).await?;
   sink.write_str("(").await?;
 
   if meta.file_type().is_file() {
@@ -131,8 +149,15 @@ where
     sink.write_str("type").await?;
     sink.write_str("directory").await?;
 
+    // entries have to be serialized in a deterministic order
+    let mut entries = vec![];
     let mut reader = fs::read_dir(path).await?;
     while let Some(file) = reader.next_entry().await? {
+      entries.push(file);
+    }
+    entries.sort_by_key(|e| e.file_name());
+
+    for file in entries {
       if filter(&file.path()) {
         sink.write_str("entry").await?;
         sink.write_str("(").await?;
@@ -148,7 +173,7 @@ where
           .await?;
 
         sink.write_str("node").await?;
-        dump_path(&file.path(), sink, filter).await?;
+        dump(&file.path(), sink, filter).await?;
         sink.write_str(")").await?;
       }
     }
@@ -157,7 +182,12 @@ where
     sink.write_str("symlink").await?;
     sink.write_str("target").await?;
     sink
-      .write_str(fs::canonicalize(path).await?.display().to_string())
+      .write_str(
+        fs::read_link(path)
+          .await?
+          .to_str()
+          .ok_or_else(|| anyhow::anyhow!("Invalid symlink target"))?,
+      )
       .await?;
   } else {
     bail!("path `{}' has an unsupported type", path.display());
@@ -197,3 +227,69 @@ pub async fn restore_into<P: AsRef<Path>, S: ByteStream + Send + Unpin>(
 ) -> Result<()> {
   sink::parse_dump(&mut RestoreSink::new(path.as_ref()), &mut source).await
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::hash::{self, HashType};
+
+  async fn nar_hash(path: &Path) -> Result<hash::Hash> {
+    let mut h = ArchiveSink::new(hash::Sink::new(HashType::SHA256));
+    dump_path(path, &mut h, &PathFilter::always()).await?;
+    Ok(h.into_inner().finish().0)
+  }
+
+  async fn write_tree(src: &Path) -> Result<()> {
+    fs::create_dir(&src).await?;
+    for name in &["b", "a", "c"] {
+      fs::write(src.join(name), name).await?;
+    }
+    fs::create_dir(src.join("d")).await?;
+    fs::write(src.join("d").join("e"), "e").await?;
+    Ok(())
+  }
+
+  #[test]
+  fn known_nar_hash() -> Result<()> {
+    crate::util::run_test(async {
+      let temp = tempfile::tempdir()?;
+      let src = temp.path().join("src");
+      write_tree(&src).await?;
+
+      // the hash Nix gives this tree, so any change to the serialization shows
+      // up here
+      assert_eq!(
+        nar_hash(&src).await?.encode(hash::Encoding::Base32),
+        "0a8d3s52mrynqcyblg9i9ckayim5gwx5klspw0sdwp7nh3l4fb0g"
+      );
+
+      Ok(())
+    })
+  }
+
+  #[test]
+  fn dump_restore_roundtrip() -> Result<()> {
+    crate::util::run_test(async {
+      let temp = tempfile::tempdir()?;
+      let src = temp.path().join("src");
+      write_tree(&src).await?;
+      fs::os::unix::symlink("../a", src.join("d").join("link")).await?;
+
+      let mut nar = ArchiveSink::new(vec![]);
+      dump_path(&src, &mut nar, &PathFilter::always()).await?;
+      let nar = nar.into_inner();
+
+      let dest = temp.path().join("dest");
+      restore_into(&dest, futures::stream::iter(nar.into_iter().map(Ok))).await?;
+
+      assert_eq!(nar_hash(&src).await?, nar_hash(&dest).await?);
+      assert_eq!(fs::read(dest.join("d").join("e")).await?, b"e");
+      assert_eq!(
+        fs::read_link(dest.join("d").join("link")).await?,
+        Path::new("../a")
+      );
+
+      Ok(())
+    })
+  }
+}
@@ -54,10 +54,9 @@ pub async fn parse_dump<S: ParseSink + Send, R: ByteStream + Send + Unpin>(
   sink: &mut S,
   source: &mut R,
 ) -> Result<()> {
-  static NAR_MAGIC: &str = "nix-archive-1";
   let mut source = source.into_async_read();
-  let vers = read_bytes_len(&mut source, NAR_MAGIC.len()).await?;
-  if vers != NAR_MAGIC.as_bytes() {
+  let vers = read_bytes_len(&mut source, super::NAR_MAGIC.len()).await?;
+  if vers != super::NAR_MAGIC.as_bytes() {
     bail!(ParseError::InvalidNar);
   }
   parse(sink, &mut source, None).await
@@ -250,8 +249,13 @@ impl ParseSink for RestoreSink {
     Ok(())
   }
 
-  async fn create_symlink(&mut self, _: Option<&Path>, _: PathBuf) -> Result<()> {
-    todo!()
+  async fn create_symlink(&mut self, path: Option<&Path>, target: PathBuf) -> Result<()> {
+    trace!(
+      "creating symlink {} -> {}",
+      self.get_path(path).display(),
+      target.display()
+    );
+    Ok(tokio::fs::os::unix::symlink(target, self.get_path(path)).await?)
   }
 
   async fn set_executable(&mut self) -> Result<()> {
@@ -1,3 +1,18 @@
+use nix_store::settings::Settings;
+
 fn main() {
+  let settings = match Settings::load() {
+    Ok(s) => s,
+    Err(e) => {
+      eprintln!("error: {:#}", e);
+      std::process::exit(1);
+    }
+  };
+
+  if std::env::args().skip(1).any(|a| a == "--show-config") {
+    print!("{}", settings);
+    return;
+  }
+
   println!("Hello, world!")
 }
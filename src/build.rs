@@ -0,0 +1,696 @@
+use crate::{
+  derivation::Derivation,
+  path::{Path as StorePath, PathSet},
+  prelude::*,
+  store::error::Error,
+  Store,
+};
+use futures::stream::{FuturesUnordered, StreamExt};
+use std::{
+  collections::{BTreeMap, BTreeSet},
+  time::SystemTime,
+};
+
+mod checks;
+mod determinism;
+mod structured_attrs;
+
+pub use checks::check_outputs;
+pub use structured_attrs::{get_structured_attrs, write_structured_attrs};
+
+#[derive(Clone, Debug)]
+pub struct BuildSettings {
+  /// Maximum number of builds to run at once.
+  pub max_jobs: usize,
+  /// Value of `NIX_BUILD_CORES` passed to each builder.
+  pub cores: usize,
+  /// Whether to keep building unrelated derivations after a failure.
+  pub keep_going: bool,
+}
+
+impl Default for BuildSettings {
+  fn default() -> Self {
+    Self {
+      max_jobs: 1,
+      cores: std::thread::available_parallelism().map_or(1, |n| n.get()),
+      keep_going: false,
+    }
+  }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BuildMode {
+  Normal,
+  /// Rebuild derivations whose outputs are already valid and compare the
+  /// results against the registered outputs.
+  Check,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BuildStatus {
+  Built,
+  AlreadyValid,
+  PermanentFailure,
+  OutputRejected,
+  NotDeterministic,
+  DependencyFailed,
+  MiscFailure,
+}
+
+#[derive(Clone, Debug)]
+pub struct BuildResult {
+  pub status: BuildStatus,
+  pub error_msg: Option<String>,
+  pub start_time: Option<SystemTime>,
+  pub stop_time: Option<SystemTime>,
+}
+
+impl BuildResult {
+  fn new(status: BuildStatus, error_msg: Option<String>) -> Self {
+    Self {
+      status,
+      error_msg,
+      start_time: None,
+      stop_time: None,
+    }
+  }
+
+  pub fn success(&self) -> bool {
+    matches!(self.status, BuildStatus::Built | BuildStatus::AlreadyValid)
+  }
+}
+
+/// Something that can run a derivation's builder. Implementations are
+/// expected to leave every output of the derivation valid in the store or, in
+/// [`BuildMode::Check`], to leave the rebuilt contents of each output next to
+/// it at `<path>.check` without registering anything.
+///
+/// Outputs must be registered with [`LocalStore::register_outputs`], which
+/// refuses outputs that break the derivation's reference restrictions before
+/// they become valid. Builders should pass its error on, so that the build
+/// fails with [`BuildStatus::OutputRejected`].
+///
+/// [`LocalStore::register_outputs`]: crate::store::local::LocalStore::register_outputs
+///
+/// Derivations using structured attrs get no environment variables from
+/// their attributes; builders should call [`write_structured_attrs`] once
+/// the build directory exists.
+#[async_trait]
+pub trait Builder: Send + Sync {
+  async fn build(
+    &self,
+    drv_path: &StorePath,
+    drv: &Derivation,
+    env: BTreeMap<String, String>,
+    mode: BuildMode,
+  ) -> Result<()>;
+}
+
+struct Goal {
+  drv: Derivation,
+  mode: BuildMode,
+  /// Input derivations that have yet to be built.
+  waitees: PathSet,
+  /// Derivations waiting on this one.
+  waiters: PathSet,
+}
+
+/// Realises a set of derivations along with any of their input derivations
+/// whose outputs are missing.
+pub struct Scheduler<'a, S, B> {
+  store: &'a S,
+  builder: &'a B,
+  settings: BuildSettings,
+}
+
+impl<'a, S: Store, B: Builder> Scheduler<'a, S, B> {
+  pub fn new(store: &'a S, builder: &'a B, settings: BuildSettings) -> Self {
+    Self {
+      store,
+      builder,
+      settings,
+    }
+  }
+
+  /// Build `drvs`. In [`BuildMode::Check`], the outputs of `drvs` must
+  /// already be valid; their inputs are built normally if necessary.
+  pub async fn build(
+    &self,
+    drvs: &PathSet,
+    mode: BuildMode,
+  ) -> Result<BTreeMap<StorePath, BuildResult>> {
+    let mut results = BTreeMap::new();
+    let mut goals = self.expand(drvs, mode, &mut results).await?;
+
+    let mut running = FuturesUnordered::new();
+    let mut started = BTreeSet::new();
+    let mut failed = false;
+
+    loop {
+      if !failed || self.settings.keep_going {
+        let ready = goals
+          .iter()
+          .filter(|(p, g)| g.waitees.is_empty() && !started.contains(*p))
+          .take(self.settings.max_jobs.saturating_sub(running.len()))
+          .map(|(p, g)| (p.clone(), g.drv.clone(), g.mode))
+          .collect::<Vec<_>>();
+        for (path, drv, mode) in ready {
+          started.insert(path.clone());
+          running.push(async move {
+            let result = self.run(&path, &drv, mode).await;
+            (path, result)
+          });
+        }
+      }
+
+      let (path, result) = match running.next().await {
+        Some(x) => x,
+        None => break,
+      };
+
+      let goal = goals
+        .remove(&path)
+        .expect("finished goal is not in the graph");
+      if result.success() {
+        for waiter in &goal.waiters {
+          if let Some(g) = goals.get_mut(waiter) {
+            g.waitees.remove(&path);
+          }
+        }
+      } else {
+        failed = true;
+        let mut dependents = goal.waiters.iter().cloned().collect::<Vec<_>>();
+        while let Some(d) = dependents.pop() {
+          if let Some(g) = goals.remove(&d) {
+            dependents.extend(g.waiters);
+            results.insert(
+              d,
+              BuildResult::new(
+                BuildStatus::DependencyFailed,
+                Some(format!("dependency `{}' failed", path)),
+              ),
+            );
+          }
+        }
+      }
+      results.insert(path, result);
+    }
+
+    for path in goals.into_keys() {
+      let msg = if failed {
+        "build was cancelled because of an earlier failure"
+      } else {
+        "build was not attempted because max-jobs is 0"
+      };
+      results.insert(
+        path,
+        BuildResult::new(BuildStatus::MiscFailure, Some(msg.into())),
+      );
+    }
+
+    Ok(results)
+  }
+
+  /// Read `drvs` and their input derivations into a dependency graph,
+  /// leaving out any whose outputs are already valid.
+  async fn expand(
+    &self,
+    drvs: &PathSet,
+    mode: BuildMode,
+    results: &mut BTreeMap<StorePath, BuildResult>,
+  ) -> Result<BTreeMap<StorePath, Goal>> {
+    let mut goals = BTreeMap::new();
+    let mut queue = drvs.iter().cloned().collect::<Vec<_>>();
+
+    while let Some(path) = queue.pop() {
+      if goals.contains_key(&path) || results.contains_key(&path) {
+        continue;
+      }
+      let drv = self.store.read_derivation(&path).await?;
+      let valid = self.outputs_valid(&drv).await?;
+      let mode = if drvs.contains(&path) {
+        mode
+      } else {
+        BuildMode::Normal
+      };
+      if mode == BuildMode::Check && !valid {
+        bail!(
+          "some outputs of `{}' are not valid, so checking is not possible",
+          self.store.print_store_path(&path)
+        );
+      }
+      if mode == BuildMode::Normal && valid {
+        debug!("all outputs of `{}' are already valid", path);
+        results.insert(path, BuildResult::new(BuildStatus::AlreadyValid, None));
+        continue;
+      }
+      queue.extend(drv.input_drvs.keys().cloned());
+      goals.insert(
+        path,
+        Goal {
+          mode,
+          waitees: drv.input_drvs.keys().cloned().collect(),
+          waiters: PathSet::new(),
+          drv,
+        },
+      );
+    }
+
+    let edges = goals
+      .iter_mut()
+      .flat_map(|(path, goal)| {
+        goal.waitees.retain(|w| !results.contains_key(w));
+        goal.waitees.iter().map(move |w| (w.clone(), path.clone()))
+      })
+      .collect::<Vec<_>>();
+    for (waitee, waiter) in edges {
+      if let Some(g) = goals.get_mut(&waitee) {
+        g.waiters.insert(waiter);
+      }
+    }
+
+    if let Some(cycle) = find_cycle(&goals) {
+      bail!(
+        "cycle detected in the derivation graph: {}",
+        cycle
+          .iter()
+          .map(|p| format!("`{}'", self.store.print_store_path(p)))
+          .collect::<Vec<_>>()
+          .join(" -> ")
+      );
+    }
+
+    Ok(goals)
+  }
+
+  async fn outputs_valid(&self, drv: &Derivation) -> Result<bool> {
+    for out in drv.outputs.values() {
+      if !self.store.is_valid_path(&out.path).await? {
+        return Ok(false);
+      }
+    }
+    Ok(true)
+  }
+
+  async fn run(&self, path: &StorePath, drv: &Derivation, mode: BuildMode) -> BuildResult {
+    let mut env = if drv.env.contains_key("__json") {
+      BTreeMap::new()
+    } else {
+      drv.env.clone()
+    };
+    env.insert("NIX_BUILD_CORES".into(), self.settings.cores.to_string());
+
+    info!("building `{}'", self.store.print_store_path(path));
+    let start_time = SystemTime::now();
+    let outcome = match self.builder.build(path, drv, env, mode).await {
+      Ok(()) if mode == BuildMode::Check => determinism::check_determinism(self.store, drv).await,
+      Ok(()) => self.check_outputs(drv).await,
+      Err(e) => Err(e),
+    };
+    let (status, error_msg) = match outcome {
+      Ok(()) => (BuildStatus::Built, None),
+      Err(e) => {
+        error!("builder for `{}' failed: {:#}", path, e);
+        let status = match e.downcast_ref::<Error>() {
+          Some(Error::ReferenceNotAllowed { .. }) => BuildStatus::OutputRejected,
+          Some(Error::NotDeterministic { .. }) => BuildStatus::NotDeterministic,
+          _ => BuildStatus::PermanentFailure,
+        };
+        (status, Some(format!("{:#}", e)))
+      }
+    };
+
+    BuildResult {
+      status,
+      error_msg,
+      start_time: Some(start_time),
+      stop_time: Some(SystemTime::now()),
+    }
+  }
+
+  async fn check_outputs(&self, drv: &Derivation) -> Result<()> {
+    for out in drv.outputs.values() {
+      if !self.store.is_valid_path(&out.path).await? {
+        bail!(
+          "builder failed to produce output path `{}'",
+          self.store.print_store_path(&out.path)
+        );
+      }
+    }
+    Ok(())
+  }
+}
+
+/// Find a cycle of goals each waiting on the next, starting and ending with
+/// the same path.
+fn find_cycle(goals: &BTreeMap<StorePath, Goal>) -> Option<Vec<StorePath>> {
+  // peel off the goals that could run eventually; whatever is left waits on
+  // a cycle, if not on one itself
+  let mut waiting = goals
+    .iter()
+    .map(|(p, g)| (p, g.waitees.len()))
+    .collect::<BTreeMap<_, _>>();
+  let mut ready = waiting
+    .iter()
+    .filter(|(_, n)| **n == 0)
+    .map(|(p, _)| *p)
+    .collect::<Vec<_>>();
+  while let Some(path) = ready.pop() {
+    waiting.remove(path);
+    for waiter in &goals[path].waiters {
+      if let Some(n) = waiting.get_mut(waiter) {
+        *n -= 1;
+        if *n == 0 {
+          ready.push(waiter);
+        }
+      }
+    }
+  }
+
+  // each remaining goal waits on another remaining one, so following those
+  // edges has to come back around
+  let mut path = *waiting.keys().next()?;
+  let mut seen = vec![];
+  while !seen.contains(&path) {
+    seen.push(path);
+    path = goals[path]
+      .waitees
+      .iter()
+      .find(|w| waiting.contains_key(w))
+      .expect("blocked goal has no blocked input");
+  }
+  let start = seen.iter().position(|p| *p == path).unwrap();
+  Some(
+    seen[start..]
+      .iter()
+      .chain(Some(&path))
+      .map(|p| (*p).clone())
+      .collect(),
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    archive::{dump_path, ArchiveSink, PathFilter},
+    hash::HashType,
+    store::local::LocalStore,
+    util::{test_derivation, test_path_info},
+  };
+  use futures::lock::Mutex;
+  use std::{
+    path::Path,
+    sync::atomic::{AtomicUsize, Ordering},
+  };
+
+  struct TestBuilder<'a> {
+    store: &'a LocalStore,
+    tmp: &'a Path,
+    log: Mutex<Vec<String>>,
+    running: AtomicUsize,
+    max_running: AtomicUsize,
+    /// Appended to the contents of outputs rebuilt in check mode.
+    salt: &'static str,
+  }
+
+  impl<'a> TestBuilder<'a> {
+    fn new(store: &'a LocalStore, tmp: &'a Path) -> Self {
+      Self {
+        store,
+        tmp,
+        log: Default::default(),
+        running: Default::default(),
+        max_running: Default::default(),
+        salt: "",
+      }
+    }
+  }
+
+  #[async_trait]
+  impl<'a> Builder for TestBuilder<'a> {
+    async fn build(
+      &self,
+      _: &StorePath,
+      drv: &Derivation,
+      env: BTreeMap<String, String>,
+      mode: BuildMode,
+    ) -> Result<()> {
+      let n = self.running.fetch_add(1, Ordering::SeqCst) + 1;
+      self.max_running.fetch_max(n, Ordering::SeqCst);
+      assert!(env.contains_key("NIX_BUILD_CORES"));
+      self.log.lock().await.push(env["name"].clone());
+      let result = if env.contains_key("fail") {
+        Err(anyhow!("builder failed on purpose"))
+      } else if mode == BuildMode::Check {
+        let check_path = format!(
+          "{}.check",
+          self.store.to_real_path(&drv.outputs["out"].path).display()
+        );
+        tokio::fs::write(check_path, format!("{}{}", env["name"], self.salt)).await?;
+        Ok(())
+      } else {
+        let file = self.tmp.join(&env["name"]);
+        tokio::fs::write(&file, &env["name"]).await?;
+        match env.get("refer") {
+          // built in place, the way a real builder does it
+          Some(refer) => {
+            let out = &drv.outputs["out"].path;
+            let real_path = self.store.to_real_path(out);
+            tokio::fs::copy(&file, &real_path).await?;
+            let mut nar = ArchiveSink::new(vec![]);
+            dump_path(&real_path, &mut nar, &PathFilter::always()).await?;
+            let nar = nar.into_inner().concat();
+            let refer = self.store.parse_store_path(Path::new(refer))?;
+            let info = test_path_info(out, &nar, &[&refer]);
+            self.store.register_outputs(drv, &[info]).await?;
+          }
+          None => {
+            let out = self
+              .store
+              .add_path_to_store(
+                &env["name"],
+                &file,
+                HashType::SHA256,
+                PathFilter::always(),
+                false,
+              )
+              .await?;
+            assert_eq!(out, drv.outputs["out"].path);
+          }
+        }
+        Ok(())
+      };
+      self.running.fetch_sub(1, Ordering::SeqCst);
+      result
+    }
+  }
+
+  async fn add_drv(
+    store: &LocalStore,
+    tmp: &Path,
+    name: &str,
+    inputs: &[&StorePath],
+    extra_env: &[(&str, &str)],
+  ) -> Result<StorePath> {
+    let file = tmp.join(name);
+    tokio::fs::write(&file, name).await?;
+    let (out, _) = store
+      .store_path_for_file(name, &file, HashType::SHA256)
+      .await?;
+    let env = Some(("name", name))
+      .into_iter()
+      .chain(extra_env.iter().copied())
+      .collect::<Vec<_>>();
+    let drv = Derivation {
+      input_drvs: inputs
+        .iter()
+        .map(|p| ((*p).clone(), Some("out".to_string()).into_iter().collect()))
+        .collect(),
+      ..test_derivation(&out, &env)
+    };
+    let drv_file = tmp.join(format!("{}.drv", name));
+    tokio::fs::write(&drv_file, drv.unparse(store)).await?;
+    store
+      .add_path_to_store(
+        &format!("{}.drv", name),
+        &drv_file,
+        HashType::SHA256,
+        PathFilter::always(),
+        false,
+      )
+      .await
+  }
+
+  #[test]
+  fn diamond() -> Result<()> {
+    crate::util::run_test(async {
+      let temp = tempfile::tempdir()?;
+      let work = tempfile::tempdir()?;
+      let store = LocalStore::open(temp.path())?;
+      let a = add_drv(&store, work.path(), "a", &[], &[]).await?;
+      let b = add_drv(&store, work.path(), "b", &[&a], &[]).await?;
+      let c = add_drv(&store, work.path(), "c", &[&a], &[]).await?;
+      let d = add_drv(&store, work.path(), "d", &[&b, &c], &[]).await?;
+
+      let builder = TestBuilder::new(&store, work.path());
+      let settings = BuildSettings {
+        max_jobs: 2,
+        ..Default::default()
+      };
+      let scheduler = Scheduler::new(&store, &builder, settings);
+      let results = scheduler
+        .build(&Some(d.clone()).into_iter().collect(), BuildMode::Normal)
+        .await?;
+
+      assert_eq!(results.len(), 4);
+      assert!(results.values().all(|r| r.status == BuildStatus::Built));
+      let log = builder.log.lock().await.clone();
+      assert_eq!(log.first().map(String::as_str), Some("a"));
+      assert_eq!(log.last().map(String::as_str), Some("d"));
+      assert!(builder.max_running.load(Ordering::SeqCst) <= 2);
+
+      let results = scheduler
+        .build(&Some(d.clone()).into_iter().collect(), BuildMode::Normal)
+        .await?;
+      assert_eq!(results.len(), 1);
+      assert_eq!(results[&d].status, BuildStatus::AlreadyValid);
+
+      Ok(())
+    })
+  }
+
+  #[test]
+  fn keep_going() -> Result<()> {
+    crate::util::run_test(async {
+      let temp = tempfile::tempdir()?;
+      let work = tempfile::tempdir()?;
+      let store = LocalStore::open(temp.path())?;
+      let a = add_drv(&store, work.path(), "a", &[], &[("fail", "1")]).await?;
+      let b = add_drv(&store, work.path(), "b", &[&a], &[]).await?;
+      let c = add_drv(&store, work.path(), "c", &[], &[]).await?;
+
+      let builder = TestBuilder::new(&store, work.path());
+      let settings = BuildSettings {
+        keep_going: true,
+        ..Default::default()
+      };
+      let results = Scheduler::new(&store, &builder, settings)
+        .build(
+          &vec![b.clone(), c.clone()].into_iter().collect(),
+          BuildMode::Normal,
+        )
+        .await?;
+
+      assert_eq!(results[&a].status, BuildStatus::PermanentFailure);
+      assert!(results[&a].error_msg.is_some());
+      assert_eq!(results[&b].status, BuildStatus::DependencyFailed);
+      assert_eq!(results[&c].status, BuildStatus::Built);
+      assert!(results[&c].start_time <= results[&c].stop_time);
+
+      Ok(())
+    })
+  }
+
+  #[test]
+  fn rejected_outputs() -> Result<()> {
+    crate::util::run_test(async {
+      let temp = tempfile::tempdir()?;
+      let work = tempfile::tempdir()?;
+      let store = LocalStore::open(temp.path())?;
+      let a = add_drv(&store, work.path(), "a", &[], &[]).await?;
+      let builder = TestBuilder::new(&store, work.path());
+      let scheduler = Scheduler::new(&store, &builder, Default::default());
+      scheduler
+        .build(&Some(a.clone()).into_iter().collect(), BuildMode::Normal)
+        .await?;
+
+      let a_out = store.read_derivation(&a).await?.outputs["out"].path.clone();
+      let a_out = store.print_store_path(&a_out);
+      let b = add_drv(
+        &store,
+        work.path(),
+        "b",
+        &[],
+        &[("refer", &a_out), ("disallowedReferences", &a_out)],
+      )
+      .await?;
+      let results = scheduler
+        .build(&Some(b.clone()).into_iter().collect(), BuildMode::Normal)
+        .await?;
+      assert_eq!(results[&b].status, BuildStatus::OutputRejected);
+      let b_out = store.read_derivation(&b).await?.outputs["out"].path.clone();
+      assert!(!store.is_valid_path(&b_out).await?);
+
+      Ok(())
+    })
+  }
+
+  #[test]
+  fn cycle() -> Result<()> {
+    crate::util::run_test(async {
+      let temp = tempfile::tempdir()?;
+      let work = tempfile::tempdir()?;
+      let store = LocalStore::open(temp.path())?;
+      let a = StorePath::from_base_name("83gajmmszj7827d54kjvk0dg8vpxspq6-a.drv")?;
+      let b = StorePath::from_base_name("x0xf8v9fxf3jk8zln1cwlsrmhqvp0f88-b.drv")?;
+      // paths can't really refer to each other like this, so write the
+      // derivations straight into the store
+      for (drv_path, input) in &[(&a, &b), (&b, &a)] {
+        let out = StorePath::from_base_name("83gajmmszj7827d54kjvk0dg8vpxspq6-out")?;
+        let drv = Derivation {
+          input_drvs: Some((
+            (*input).clone(),
+            Some("out".to_string()).into_iter().collect(),
+          ))
+          .into_iter()
+          .collect(),
+          ..test_derivation(&out, &[])
+        };
+        tokio::fs::write(store.to_real_path(drv_path), drv.unparse(&store)).await?;
+      }
+
+      let builder = TestBuilder::new(&store, work.path());
+      let err = Scheduler::new(&store, &builder, Default::default())
+        .build(&Some(a.clone()).into_iter().collect(), BuildMode::Normal)
+        .await
+        .unwrap_err();
+      let msg = err.to_string();
+      assert!(msg.contains("cycle detected"), "{}", msg);
+      assert!(msg.contains(&store.print_store_path(&b)), "{}", msg);
+      assert!(builder.log.lock().await.is_empty());
+
+      Ok(())
+    })
+  }
+
+  #[test]
+  fn check_mode() -> Result<()> {
+    crate::util::run_test(async {
+      let temp = tempfile::tempdir()?;
+      let work = tempfile::tempdir()?;
+      let store = LocalStore::open(temp.path())?;
+      let a = add_drv(&store, work.path(), "a", &[], &[]).await?;
+      let drvs = Some(a.clone()).into_iter().collect::<PathSet>();
+
+      let mut builder = TestBuilder::new(&store, work.path());
+      let scheduler = Scheduler::new(&store, &builder, Default::default());
+      assert!(scheduler.build(&drvs, BuildMode::Check).await.is_err());
+      scheduler.build(&drvs, BuildMode::Normal).await?;
+
+      let results = scheduler.build(&drvs, BuildMode::Check).await?;
+      assert_eq!(results[&a].status, BuildStatus::Built);
+
+      builder.salt = "!";
+      let results = Scheduler::new(&store, &builder, Default::default())
+        .build(&drvs, BuildMode::Check)
+        .await?;
+      assert_eq!(results[&a].status, BuildStatus::NotDeterministic);
+      let out = store.read_derivation(&a).await?.outputs["out"].path.clone();
+      let check_path = format!("{}.check", store.to_real_path(&out).display());
+      assert_eq!(tokio::fs::read(check_path).await?, b"a!");
+
+      Ok(())
+    })
+  }
+}
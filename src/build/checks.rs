@@ -0,0 +1,231 @@
+use crate::{
+  derivation::Derivation,
+  path::{Path as StorePath, PathSet},
+  path_info::ValidPathInfo,
+  prelude::*,
+  store::error::Error,
+  Store,
+};
+use std::{collections::BTreeMap, path::Path};
+
+/// Each output check, whether it lists allowed (rather than disallowed)
+/// paths, and whether it applies to the whole closure of the output.
+static CHECKS: [(&str, bool, bool); 4] = [
+  ("allowedReferences", true, false),
+  ("allowedRequisites", true, true),
+  ("disallowedReferences", false, false),
+  ("disallowedRequisites", false, true),
+];
+
+/// Enforce `drv`'s reference restrictions on its freshly built `outputs`,
+/// which need not be registered yet.
+pub async fn check_outputs<S: Store>(
+  store: &S,
+  drv: &Derivation,
+  outputs: &[ValidPathInfo],
+) -> Result<()> {
+  let new = outputs
+    .iter()
+    .map(|i| (&i.store_path, i))
+    .collect::<BTreeMap<_, _>>();
+
+  for info in outputs {
+    for &(attr, allowed, recursive) in CHECKS.iter() {
+      let spec = match drv.env.get(attr) {
+        Some(value) => parse_reference_specifiers(store, drv, attr, value)?,
+        None => continue,
+      };
+
+      // maps each used path to the path that refers to it
+      let used = if recursive {
+        closure(store, &new, &info.store_path).await?
+      } else {
+        info
+          .references
+          .iter()
+          .filter(|r| **r != info.store_path)
+          .map(|r| (r.clone(), Some(info.store_path.clone())))
+          .collect()
+      };
+
+      for path in used.keys() {
+        if *path == info.store_path || spec.contains(path) == allowed {
+          continue;
+        }
+        let mut chain = vec![store.print_store_path(path).into()];
+        let mut cur = path;
+        while let Some(Some(parent)) = used.get(cur) {
+          chain.push(store.print_store_path(parent).into());
+          cur = parent;
+        }
+        chain.reverse();
+        bail!(Error::ReferenceNotAllowed {
+          output: store.print_store_path(&info.store_path).into(),
+          path: store.print_store_path(path).into(),
+          chain,
+          attr,
+        });
+      }
+    }
+  }
+
+  Ok(())
+}
+
+fn parse_reference_specifiers<S: Store>(
+  store: &S,
+  drv: &Derivation,
+  attr: &'static str,
+  value: &str,
+) -> Result<PathSet> {
+  value
+    .split_whitespace()
+    .map(|spec| {
+      if Path::new(spec).starts_with(store.store_path()) {
+        store.parse_store_path(Path::new(spec))
+      } else if let Some(out) = drv.outputs.get(spec) {
+        Ok(out.path.clone())
+      } else {
+        Err(
+          Error::IllegalReferenceSpecifier {
+            spec: spec.into(),
+            attr,
+          }
+          .into(),
+        )
+      }
+    })
+    .collect()
+}
+
+/// Compute the closure of `path`, looking up references in `new` before
+/// asking the store.
+async fn closure<S: Store>(
+  store: &S,
+  new: &BTreeMap<&StorePath, &ValidPathInfo>,
+  path: &StorePath,
+) -> Result<BTreeMap<StorePath, Option<StorePath>>> {
+  let mut seen = BTreeMap::new();
+  seen.insert(path.clone(), None);
+  let mut queue = vec![path.clone()];
+
+  while let Some(p) = queue.pop() {
+    let references = match new.get(&p) {
+      Some(info) => info.references.clone(),
+      None => store
+        .get_path_info(&p)
+        .await?
+        .ok_or_else(|| anyhow!("path `{}' is not valid", store.print_store_path(&p)))?
+        .references()
+        .clone(),
+    };
+    for r in references {
+      if !seen.contains_key(&r) {
+        seen.insert(r.clone(), Some(p.clone()));
+        queue.push(r);
+      }
+    }
+  }
+
+  Ok(seen)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    archive::PathFilter,
+    hash::HashType,
+    store::local::LocalStore,
+    util::{test_derivation, test_path_info},
+  };
+
+  #[test]
+  fn reference_checks() -> Result<()> {
+    crate::util::run_test(async {
+      let temp = tempfile::tempdir()?;
+      let store = LocalStore::open(temp.path())?;
+      let dep = store
+        .add_path_to_store(
+          "Cargo.toml",
+          Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/Cargo.toml")),
+          HashType::SHA256,
+          PathFilter::always(),
+          false,
+        )
+        .await?;
+      let mid = StorePath::from_base_name("x0xf8v9fxf3jk8zln1cwlsrmhqvp0f88-mid")?;
+      store
+        .register_outputs(
+          &test_derivation(&mid, &[]),
+          &[test_path_info(&mid, b"", &[&dep])],
+        )
+        .await?;
+      let out = StorePath::from_base_name("83gajmmszj7827d54kjvk0dg8vpxspq6-out")?;
+      let out_info = test_path_info(&out, b"", &[&out, &mid]);
+
+      let err = store
+        .register_outputs(
+          &test_derivation(
+            &out,
+            &[(
+              "disallowedRequisites",
+              store.print_store_path(&dep).as_str(),
+            )],
+          ),
+          std::slice::from_ref(&out_info),
+        )
+        .await
+        .unwrap_err();
+      match err.downcast_ref::<Error>() {
+        Some(Error::ReferenceNotAllowed { path, chain, .. }) => {
+          assert_eq!(*path, PathBuf::from(store.print_store_path(&dep)));
+          assert_eq!(chain.len(), 3);
+        }
+        _ => panic!("unexpected error {}", err),
+      }
+
+      let err = store
+        .register_outputs(
+          &test_derivation(&out, &[("allowedReferences", "out")]),
+          std::slice::from_ref(&out_info),
+        )
+        .await
+        .unwrap_err();
+      assert_matches::assert_matches!(
+        err.downcast_ref::<Error>(),
+        Some(Error::ReferenceNotAllowed {
+          attr: "allowedReferences",
+          ..
+        })
+      );
+
+      let err = store
+        .register_outputs(
+          &test_derivation(&out, &[("allowedReferences", "dev")]),
+          std::slice::from_ref(&out_info),
+        )
+        .await
+        .unwrap_err();
+      assert_matches::assert_matches!(
+        err.downcast_ref::<Error>(),
+        Some(Error::IllegalReferenceSpecifier { .. })
+      );
+
+      assert!(!store.is_valid_path(&out).await?);
+      store
+        .register_outputs(
+          &test_derivation(
+            &out,
+            &[("allowedReferences", store.print_store_path(&mid).as_str())],
+          ),
+          &[out_info],
+        )
+        .await?;
+      let registered = store.get_path_info(&out).await?.unwrap();
+      assert_eq!(registered.references().len(), 2);
+
+      Ok(())
+    })
+  }
+}
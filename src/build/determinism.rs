@@ -0,0 +1,95 @@
+use crate::{
+  archive::{ArchiveSink, PathFilter},
+  derivation::Derivation,
+  prelude::*,
+  store::error::Error,
+  Store,
+};
+use std::{
+  collections::BTreeSet,
+  os::unix::fs::PermissionsExt,
+  path::{Path, PathBuf},
+};
+use tokio::fs;
+
+/// Compare the rebuilt outputs of `drv`, found at `<path>.check`, with the
+/// registered ones. Matching rebuilds are deleted; differing ones are kept.
+pub async fn check_determinism<S: Store>(store: &S, drv: &Derivation) -> Result<()> {
+  for out in drv.outputs.values() {
+    let path = store.to_real_path(&out.path);
+    let check_path = PathBuf::from(format!("{}.check", path.display()));
+    let info = store
+      .get_path_info(&out.path)
+      .await?
+      .ok_or_else(|| anyhow!("path `{}' is not valid", path.display()))?;
+
+    let mut sink = ArchiveSink::new(crate::hash::Sink::new(info.nar_hash().type_()));
+    crate::archive::dump_path(&check_path, &mut sink, &PathFilter::always())
+      .await
+      .with_context(|| format!("while hashing rebuilt output `{}'", check_path.display()))?;
+    let (hash, _) = sink.into_inner().finish();
+
+    if hash == *info.nar_hash() {
+      delete_path(&check_path).await?;
+      continue;
+    }
+
+    let mut differing = vec![];
+    diff_paths(&path, &check_path, &mut differing).await?;
+    bail!(Error::NotDeterministic {
+      path,
+      check_path,
+      differing,
+    });
+  }
+
+  Ok(())
+}
+
+async fn delete_path(path: &Path) -> Result<()> {
+  if fs::symlink_metadata(path).await?.is_dir() {
+    fs::remove_dir_all(path).await?;
+  } else {
+    fs::remove_file(path).await?;
+  }
+  Ok(())
+}
+
+/// Collect the files under `a` which differ from their counterparts under `b`
+/// in type, contents, executable bit or symlink target.
+#[async_recursion]
+async fn diff_paths(a: &Path, b: &Path, differing: &mut Vec<PathBuf>) -> Result<()> {
+  let (ma, mb) = match (fs::symlink_metadata(a).await, fs::symlink_metadata(b).await) {
+    (Ok(ma), Ok(mb)) => (ma, mb),
+    _ => {
+      differing.push(a.into());
+      return Ok(());
+    }
+  };
+
+  let same = if ma.is_dir() && mb.is_dir() {
+    let mut names = BTreeSet::new();
+    for dir in &[a, b] {
+      let mut entries = fs::read_dir(dir).await?;
+      while let Some(entry) = entries.next_entry().await? {
+        names.insert(entry.file_name());
+      }
+    }
+    for name in names {
+      diff_paths(&a.join(&name), &b.join(&name), differing).await?;
+    }
+    true
+  } else if ma.is_file() && mb.is_file() {
+    (ma.permissions().mode() & 0o100) == (mb.permissions().mode() & 0o100)
+      && fs::read(a).await? == fs::read(b).await?
+  } else if ma.file_type().is_symlink() && mb.file_type().is_symlink() {
+    fs::read_link(a).await? == fs::read_link(b).await?
+  } else {
+    false
+  };
+
+  if !same {
+    differing.push(a.into());
+  }
+  Ok(())
+}
@@ -0,0 +1,226 @@
+use crate::{derivation::Derivation, hash::Encoding, path::PathSet, prelude::*, Store};
+use serde_json::{Map, Value};
+use std::{collections::BTreeMap, path::Path};
+use tokio::fs;
+
+/// Parse the attributes of a derivation that sets `__structuredAttrs`, which
+/// are passed to us as JSON in the `__json` environment variable.
+pub fn get_structured_attrs(drv: &Derivation) -> Result<Option<Map<String, Value>>> {
+  match drv.env.get("__json") {
+    None => Ok(None),
+    Some(json) => match serde_json::from_str(json)
+      .context("while parsing the `__json' attribute of a derivation")?
+    {
+      Value::Object(attrs) => Ok(Some(attrs)),
+      _ => bail!("the `__json' attribute of a derivation is not an object"),
+    },
+  }
+}
+
+/// Write `.attrs.json` and `.attrs.sh` for a derivation using structured
+/// attrs into `build_dir`, and point the builder at them through `env`. Does
+/// nothing for other derivations.
+pub async fn write_structured_attrs<S: Store>(
+  store: &S,
+  drv: &Derivation,
+  build_dir: &Path,
+  env: &mut BTreeMap<String, String>,
+) -> Result<()> {
+  let mut json = match get_structured_attrs(drv)? {
+    Some(attrs) => attrs,
+    None => return Ok(()),
+  };
+
+  json.insert(
+    "outputs".into(),
+    Value::Object(
+      drv
+        .outputs
+        .iter()
+        .map(|(id, out)| (id.clone(), store.print_store_path(&out.path).into()))
+        .collect(),
+    ),
+  );
+
+  if let Some(graphs) = json.get("exportReferencesGraph").cloned() {
+    let graphs = match graphs {
+      Value::Object(o) => o,
+      _ => bail!("`exportReferencesGraph' must be an attribute set"),
+    };
+    for (name, paths) in graphs {
+      let roots = match paths {
+        Value::Array(a) => a,
+        _ => bail!("`exportReferencesGraph.{}' must be a list of paths", name),
+      }
+      .iter()
+      .map(|p| match p {
+        Value::String(s) => store.parse_store_path(Path::new(s)),
+        _ => bail!("`exportReferencesGraph.{}' must be a list of paths", name),
+      })
+      .collect::<Result<PathSet>>()?;
+      json.insert(name, closure_to_json(store, &roots).await?);
+    }
+  }
+
+  let sh_file = build_dir.join(".attrs.sh");
+  fs::write(&sh_file, to_shell(&json)).await?;
+  env.insert("NIX_ATTRS_SH_FILE".into(), sh_file.display().to_string());
+
+  let json_file = build_dir.join(".attrs.json");
+  fs::write(&json_file, Value::Object(json).to_string()).await?;
+  env.insert(
+    "NIX_ATTRS_JSON_FILE".into(),
+    json_file.display().to_string(),
+  );
+
+  Ok(())
+}
+
+async fn closure_to_json<S: Store>(store: &S, roots: &PathSet) -> Result<Value> {
+  let mut infos = vec![];
+  for path in store.compute_fs_closure(roots).await? {
+    let info = store
+      .get_path_info(&path)
+      .await?
+      .ok_or_else(|| anyhow!("path `{}' is not valid", store.print_store_path(&path)))?;
+    let mut obj = Map::new();
+    obj.insert("path".into(), store.print_store_path(&path).into());
+    obj.insert(
+      "narHash".into(),
+      info.nar_hash().encode_with_type(Encoding::Base32).into(),
+    );
+    obj.insert("narSize".into(), info.nar_size().unwrap_or(0).into());
+    obj.insert(
+      "references".into(),
+      info
+        .references()
+        .iter()
+        .map(|r| Value::from(store.print_store_path(r)))
+        .collect(),
+    );
+    infos.push(Value::Object(obj));
+  }
+  Ok(Value::Array(infos))
+}
+
+/// Render the attributes that bash can represent as `declare` statements.
+/// Anything else (nested structures, non-integral numbers) is left out.
+fn to_shell(json: &Map<String, Value>) -> String {
+  let mut sh = String::new();
+  for (key, value) in json {
+    if !is_shell_name(key) {
+      continue;
+    }
+    if let Some(s) = simple_value(value) {
+      sh.push_str(&format!("declare {}={}\n", key, s));
+    } else if let Value::Array(a) = value {
+      if let Some(items) = a.iter().map(simple_value).collect::<Option<Vec<_>>>() {
+        let items = items.into_iter().map(|i| i + " ").collect::<String>();
+        sh.push_str(&format!("declare -a {}=({})\n", key, items));
+      }
+    } else if let Value::Object(o) = value {
+      if let Some(items) = o
+        .iter()
+        .map(|(k, v)| Some(format!("[{}]={} ", shell_escape(k), simple_value(v)?)))
+        .collect::<Option<String>>()
+      {
+        sh.push_str(&format!("declare -A {}=({})\n", key, items));
+      }
+    }
+  }
+  sh
+}
+
+fn simple_value(value: &Value) -> Option<String> {
+  match value {
+    Value::String(s) => Some(shell_escape(s)),
+    Value::Number(n) => n.as_i64().map(|i| i.to_string()),
+    Value::Null => Some("''".into()),
+    Value::Bool(b) => Some(if *b { "1" } else { "" }.into()),
+    _ => None,
+  }
+}
+
+fn shell_escape(s: &str) -> String {
+  format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+fn is_shell_name(s: &str) -> bool {
+  let mut chars = s.chars();
+  chars
+    .next()
+    .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+    && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn shell_rendering() {
+    let json = serde_json::json!({
+      "name": "it's",
+      "jobs": 4,
+      "ratio": 0.5,
+      "doCheck": true,
+      "nothing": null,
+      "srcs": ["a", "b"],
+      "mixed": ["a", {}],
+      "env": { "FOO": "bar" },
+      "not-a-name": "x",
+    });
+    let sh = to_shell(json.as_object().unwrap());
+    let lines = sh.lines().collect::<Vec<_>>();
+    assert_eq!(
+      lines,
+      vec![
+        "declare doCheck=1",
+        "declare -A env=(['FOO']='bar' )",
+        "declare jobs=4",
+        "declare name='it'\\''s'",
+        "declare nothing=''",
+        "declare -a srcs=('a' 'b' )",
+      ]
+    );
+  }
+
+  #[test]
+  fn attrs_files() -> Result<()> {
+    crate::util::run_test(async {
+      use crate::{archive::PathFilter, hash::HashType, store::local::LocalStore};
+
+      let temp = tempfile::tempdir()?;
+      let build_dir = tempfile::tempdir()?;
+      let store = LocalStore::open(temp.path())?;
+      let dep = store
+        .add_path_to_store(
+          "Cargo.toml",
+          Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/Cargo.toml")),
+          HashType::SHA256,
+          PathFilter::always(),
+          false,
+        )
+        .await?;
+      let out = StorePath::from_base_name("83gajmmszj7827d54kjvk0dg8vpxspq6-out")?;
+      let attrs = serde_json::json!({
+        "name": "out",
+        "exportReferencesGraph": { "deps": [store.print_store_path(&dep)] },
+      });
+      let drv = crate::util::test_derivation(&out, &[("__json", &attrs.to_string())]);
+
+      let mut env = BTreeMap::new();
+      write_structured_attrs(&store, &drv, build_dir.path(), &mut env).await?;
+      assert!(env.contains_key("NIX_ATTRS_SH_FILE"));
+
+      let json: Value = serde_json::from_slice(&fs::read(&env["NIX_ATTRS_JSON_FILE"]).await?)?;
+      assert_eq!(json["outputs"]["out"], store.print_store_path(&out));
+      assert_eq!(json["deps"][0]["path"], store.print_store_path(&dep));
+
+      let sh = fs::read_to_string(build_dir.path().join(".attrs.sh")).await?;
+      assert!(sh.contains("declare name='out'"));
+
+      Ok(())
+    })
+  }
+}
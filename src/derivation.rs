@@ -0,0 +1,323 @@
+use crate::{
+  path::{Path as StorePath, PathSet},
+  prelude::*,
+  Store,
+};
+use std::{
+  collections::{BTreeMap, BTreeSet},
+  path::Path,
+};
+
+#[derive(Debug, Error)]
+pub enum Error {
+  #[error("expected string `{0}' in derivation")]
+  Expected(&'static str),
+  #[error("unexpected end of derivation")]
+  UnexpectedEof,
+  #[error("trailing garbage at the end of derivation")]
+  TrailingGarbage,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DerivationOutput {
+  pub path: StorePath,
+  /// Empty unless this is a fixed-output derivation. May be prefixed with
+  /// `r:` for recursive hashing.
+  pub hash_algo: String,
+  pub hash: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Derivation {
+  pub outputs: BTreeMap<String, DerivationOutput>,
+  pub input_drvs: BTreeMap<StorePath, BTreeSet<String>>,
+  pub input_srcs: PathSet,
+  pub platform: String,
+  pub builder: String,
+  pub args: Vec<String>,
+  pub env: BTreeMap<String, String>,
+}
+
+impl Derivation {
+  pub fn parse<S: Store + ?Sized>(store: &S, s: &str) -> Result<Self> {
+    let mut p = Parser(s.as_bytes());
+    p.expect("Derive([")?;
+
+    let mut outputs = BTreeMap::new();
+    let mut first = true;
+    while !p.end_of_list(&mut first)? {
+      p.expect("(")?;
+      let id = p.string()?;
+      p.expect(",")?;
+      let path = p.path(store)?;
+      p.expect(",")?;
+      let hash_algo = p.string()?;
+      p.expect(",")?;
+      let hash = p.string()?;
+      p.expect(")")?;
+      outputs.insert(
+        id,
+        DerivationOutput {
+          path,
+          hash_algo,
+          hash,
+        },
+      );
+    }
+
+    p.expect(",[")?;
+    let mut input_drvs = BTreeMap::new();
+    let mut first = true;
+    while !p.end_of_list(&mut first)? {
+      p.expect("(")?;
+      let path = p.path(store)?;
+      p.expect(",[")?;
+      let mut ids = BTreeSet::new();
+      let mut first_id = true;
+      while !p.end_of_list(&mut first_id)? {
+        ids.insert(p.string()?);
+      }
+      p.expect(")")?;
+      input_drvs.insert(path, ids);
+    }
+
+    p.expect(",[")?;
+    let mut input_srcs = PathSet::new();
+    let mut first = true;
+    while !p.end_of_list(&mut first)? {
+      input_srcs.insert(p.path(store)?);
+    }
+
+    p.expect(",")?;
+    let platform = p.string()?;
+    p.expect(",")?;
+    let builder = p.string()?;
+
+    p.expect(",[")?;
+    let mut args = vec![];
+    let mut first = true;
+    while !p.end_of_list(&mut first)? {
+      args.push(p.string()?);
+    }
+
+    p.expect(",[")?;
+    let mut env = BTreeMap::new();
+    let mut first = true;
+    while !p.end_of_list(&mut first)? {
+      p.expect("(")?;
+      let name = p.string()?;
+      p.expect(",")?;
+      let value = p.string()?;
+      p.expect(")")?;
+      env.insert(name, value);
+    }
+
+    p.expect(")")?;
+    if !p.0.is_empty() {
+      bail!(Error::TrailingGarbage);
+    }
+
+    Ok(Self {
+      outputs,
+      input_drvs,
+      input_srcs,
+      platform,
+      builder,
+      args,
+      env,
+    })
+  }
+
+  /// Serialize to the ATerm format used by `.drv` files.
+  pub fn unparse<S: Store + ?Sized>(&self, store: &S) -> String {
+    let mut s = String::from("Derive([");
+    for (i, (id, out)) in self.outputs.iter().enumerate() {
+      if i > 0 {
+        s.push(',');
+      }
+      s.push('(');
+      print_string(&mut s, id);
+      s.push(',');
+      print_string(&mut s, &store.print_store_path(&out.path));
+      s.push(',');
+      print_string(&mut s, &out.hash_algo);
+      s.push(',');
+      print_string(&mut s, &out.hash);
+      s.push(')');
+    }
+
+    s.push_str("],[");
+    for (i, (path, ids)) in self.input_drvs.iter().enumerate() {
+      if i > 0 {
+        s.push(',');
+      }
+      s.push('(');
+      print_string(&mut s, &store.print_store_path(path));
+      s.push(',');
+      print_strings(&mut s, ids);
+      s.push(')');
+    }
+
+    s.push_str("],");
+    print_strings(
+      &mut s,
+      self.input_srcs.iter().map(|p| store.print_store_path(p)),
+    );
+    s.push(',');
+    print_string(&mut s, &self.platform);
+    s.push(',');
+    print_string(&mut s, &self.builder);
+    s.push(',');
+    print_strings(&mut s, &self.args);
+
+    s.push_str(",[");
+    for (i, (name, value)) in self.env.iter().enumerate() {
+      if i > 0 {
+        s.push(',');
+      }
+      s.push('(');
+      print_string(&mut s, name);
+      s.push(',');
+      print_string(&mut s, value);
+      s.push(')');
+    }
+    s.push_str("])");
+
+    s
+  }
+
+  pub fn is_fixed_output(&self) -> bool {
+    self.outputs.len() == 1 && self.outputs.get("out").is_some_and(|o| !o.hash.is_empty())
+  }
+
+  pub fn output_paths(&self) -> PathSet {
+    self.outputs.values().map(|o| o.path.clone()).collect()
+  }
+}
+
+struct Parser<'a>(&'a [u8]);
+
+impl<'a> Parser<'a> {
+  fn expect(&mut self, s: &'static str) -> Result<()> {
+    if !self.0.starts_with(s.as_bytes()) {
+      bail!(Error::Expected(s));
+    }
+    self.0 = &self.0[s.len()..];
+    Ok(())
+  }
+
+  /// Consume the closing bracket of a list and return true, or else the
+  /// separator before the next item unless it's the `first` one.
+  fn end_of_list(&mut self, first: &mut bool) -> Result<bool> {
+    if let [b']', rest @ ..] = self.0 {
+      self.0 = rest;
+      return Ok(true);
+    }
+    if !std::mem::replace(first, false) {
+      self.expect(",")?;
+    }
+    Ok(false)
+  }
+
+  fn string(&mut self) -> Result<String> {
+    self.expect("\"")?;
+    let mut out = vec![];
+    loop {
+      match self.0 {
+        [] | [b'\\'] => bail!(Error::UnexpectedEof),
+        [b'"', rest @ ..] => {
+          self.0 = rest;
+          break;
+        }
+        [b'\\', c, rest @ ..] => {
+          out.push(match c {
+            b'n' => b'\n',
+            b'r' => b'\r',
+            b't' => b'\t',
+            c => *c,
+          });
+          self.0 = rest;
+        }
+        [c, rest @ ..] => {
+          out.push(*c);
+          self.0 = rest;
+        }
+      }
+    }
+    Ok(String::from_utf8(out)?)
+  }
+
+  fn path<S: Store + ?Sized>(&mut self, store: &S) -> Result<StorePath> {
+    let s = self.string()?;
+    store.parse_store_path(Path::new(&s))
+  }
+}
+
+fn print_string(buf: &mut String, s: &str) {
+  buf.push('"');
+  for c in s.chars() {
+    match c {
+      '"' => buf.push_str("\\\""),
+      '\\' => buf.push_str("\\\\"),
+      '\n' => buf.push_str("\\n"),
+      '\r' => buf.push_str("\\r"),
+      '\t' => buf.push_str("\\t"),
+      c => buf.push(c),
+    }
+  }
+  buf.push('"');
+}
+
+fn print_strings<S: AsRef<str>, I: IntoIterator<Item = S>>(buf: &mut String, strings: I) {
+  buf.push('[');
+  for (i, s) in strings.into_iter().enumerate() {
+    if i > 0 {
+      buf.push(',');
+    }
+    print_string(buf, s.as_ref());
+  }
+  buf.push(']');
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::store::local::LocalStore;
+
+  #[test]
+  fn roundtrip() -> Result<()> {
+    let temp = tempfile::tempdir()?;
+    let store = LocalStore::open(temp.path())?;
+    let out = store.print_store_path(&StorePath::from_base_name(
+      "83gajmmszj7827d54kjvk0dg8vpxspq6-hello",
+    )?);
+    let dep = store.print_store_path(&StorePath::from_base_name(
+      "x0xf8v9fxf3jk8zln1cwlsrmhqvp0f88-dep.drv",
+    )?);
+    let input = format!(
+      "Derive([(\"out\",\"{out}\",\"\",\"\")],[(\"{dep}\",[\"dev\",\"out\"])],[],\"x86_64-linux\",\
+       \"/bin/sh\",[\"-c\",\"echo \\\"hi\\\"\\n\"],[(\"name\",\"hello\"),(\"out\",\"{out}\")])",
+      out = out,
+      dep = dep
+    );
+
+    let drv = Derivation::parse(&store, &input)?;
+    assert_eq!(drv.args, vec!["-c", "echo \"hi\"\n"]);
+    assert_eq!(drv.input_drvs.values().next().unwrap().len(), 2);
+    assert_eq!(drv.env["name"], "hello");
+    assert!(!drv.is_fixed_output());
+    assert_eq!(drv.unparse(&store), input);
+
+    assert!(Derivation::parse(&store, &input[..input.len() - 1]).is_err());
+    // list items need a separator between them
+    let input = input.replacen("\"-c\",", "\"-c\"", 1);
+    assert_matches::assert_matches!(
+      Derivation::parse(&store, &input)
+        .unwrap_err()
+        .downcast::<Error>(),
+      Ok(Error::Expected(","))
+    );
+
+    Ok(())
+  }
+}
@@ -5,10 +5,13 @@
 
 pub mod archive;
 pub mod base32;
+pub mod build;
+pub mod derivation;
 pub mod hash;
 pub mod path;
 pub mod path_info;
 mod prelude;
+pub mod settings;
 pub mod sqlite;
 pub mod store;
 pub mod util;
@@ -57,6 +57,10 @@ impl Path {
     })
   }
 
+  pub fn is_derivation(&self) -> bool {
+    self.name.ends_with(".drv")
+  }
+
   pub fn from_base_name(base_name: &str) -> Result<Self> {
     if base_name.len() < HASH_CHARS + 1 || base_name.as_bytes()[HASH_CHARS] != b'-' {
       bail!(Error::InvalidFilepath(base_name.into()));
@@ -3,6 +3,10 @@ use std::{collections::BTreeSet, time::SystemTime};
 
 pub trait PathInfo: Send + Sync {
   fn store_path(&self) -> &Path;
+  fn deriver(&self) -> Option<&Path>;
+  fn references(&self) -> &BTreeSet<Path>;
+  fn nar_hash(&self) -> &Hash;
+  fn nar_size(&self) -> Option<u64>;
 }
 
 #[derive(Clone, Debug)]
@@ -33,4 +37,20 @@ impl PathInfo for ValidPathInfo {
   fn store_path(&self) -> &Path {
     &self.store_path
   }
+
+  fn deriver(&self) -> Option<&Path> {
+    self.deriver.as_ref()
+  }
+
+  fn references(&self) -> &BTreeSet<Path> {
+    &self.references
+  }
+
+  fn nar_hash(&self) -> &Hash {
+    &self.nar_hash
+  }
+
+  fn nar_size(&self) -> Option<u64> {
+    self.nar_size
+  }
 }
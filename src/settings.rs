@@ -0,0 +1,351 @@
+//! Configuration, read from `nix.conf` and the environment the way Nix does
+//! it.
+
+use crate::{
+  prelude::*,
+  store::local::{AutoGcSettings, DbConfig, Dirs, GcOptions, Synchronous},
+};
+use std::{ffi::OsString, fmt, path::Path, time::Duration};
+
+#[derive(Debug, Error)]
+pub enum Error {
+  #[error("unknown setting `{name}' in {origin}")]
+  UnknownSetting { name: String, origin: String },
+  #[error("invalid value `{value}' for setting `{name}' in {origin}")]
+  InvalidValue {
+    name: String,
+    value: String,
+    origin: String,
+  },
+  #[error("syntax error in configuration line `{line}' in {origin}")]
+  Syntax { line: String, origin: String },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Settings {
+  /// The store directory as store paths know it. `NIX_STORE_DIR`.
+  pub store_dir: PathBuf,
+  /// `NIX_STATE_DIR`.
+  pub state_dir: PathBuf,
+  /// `NIX_LOG_DIR`.
+  pub log_dir: PathBuf,
+  /// `min-free`: collect garbage when fewer bytes than this are free.
+  pub min_free: u64,
+  /// `max-free`: stop collecting garbage once this many bytes are free.
+  pub max_free: u64,
+  /// `min-free-check-interval`, in seconds.
+  pub min_free_check_interval: u64,
+  /// `auto-optimise-store`.
+  pub auto_optimise_store: bool,
+  /// `keep-outputs`.
+  pub keep_outputs: bool,
+  /// `keep-derivations`.
+  pub keep_derivations: bool,
+  /// `gc-reserved-space`.
+  pub gc_reserved_space: u64,
+  /// `fsync-metadata`: whether the database waits for its writes to reach
+  /// the disk.
+  pub fsync_metadata: bool,
+  /// `sqlite-busy-timeout`, in seconds.
+  pub sqlite_busy_timeout: u64,
+  /// `sqlite-wal-autocheckpoint`, in pages.
+  pub sqlite_wal_autocheckpoint: i64,
+  /// `path-info-cache-size`: how many path infos `Cached` keeps in memory.
+  pub path_info_cache_size: usize,
+}
+
+impl Default for Settings {
+  fn default() -> Self {
+    let db = DbConfig::default();
+    let auto_gc = AutoGcSettings::default();
+    Self {
+      store_dir: "/nix/store".into(),
+      state_dir: "/nix/var/nix".into(),
+      log_dir: "/nix/var/log/nix".into(),
+      min_free: auto_gc.min_free,
+      max_free: auto_gc.max_free,
+      min_free_check_interval: auto_gc.check_interval.as_secs(),
+      auto_optimise_store: false,
+      keep_outputs: false,
+      keep_derivations: true,
+      gc_reserved_space: crate::store::local::RESERVED_SPACE,
+      fsync_metadata: true,
+      sqlite_busy_timeout: db.busy_timeout.as_secs(),
+      sqlite_wal_autocheckpoint: db.wal_autocheckpoint,
+      path_info_cache_size: 8192,
+    }
+  }
+}
+
+impl Settings {
+  /// The settings that Nix would use here: the defaults, then
+  /// `$NIX_CONF_DIR/nix.conf` (`/etc/nix/nix.conf` by default), then
+  /// `NIX_CONFIG`, and then the directories from `NIX_STORE_DIR`,
+  /// `NIX_STATE_DIR` and `NIX_LOG_DIR`.
+  pub fn load() -> Result<Self> {
+    Self::load_from_env(|name| std::env::var_os(name))
+  }
+
+  /// Like `load`, but with the environment variables that `env` gives.
+  pub fn load_from_env<F: Fn(&str) -> Option<OsString>>(env: F) -> Result<Self> {
+    let mut settings = Self::default();
+    let conf_dir = env("NIX_CONF_DIR").map_or_else(|| PathBuf::from("/etc/nix"), PathBuf::from);
+    settings.read_file(&conf_dir.join("nix.conf"), false)?;
+    if let Some(config) = env("NIX_CONFIG") {
+      settings.apply_config(&config.to_string_lossy(), "`NIX_CONFIG'", &conf_dir)?;
+    }
+    if let Some(dir) = env("NIX_STORE_DIR") {
+      settings.store_dir = dir.into();
+    }
+    if let Some(dir) = env("NIX_STATE_DIR") {
+      settings.state_dir = dir.into();
+    }
+    if let Some(dir) = env("NIX_LOG_DIR") {
+      settings.log_dir = dir.into();
+    }
+    Ok(settings)
+  }
+
+  /// Apply the configuration file `path`. Unless `must_exist` is set, it's
+  /// fine for it not to be there.
+  pub fn read_file(&mut self, path: &Path, must_exist: bool) -> Result<()> {
+    let contents = match std::fs::read_to_string(path) {
+      Ok(c) => c,
+      Err(e) if !must_exist && e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+      Err(e) => {
+        return Err(e).with_context(|| format!("while reading `{}'", path.display()));
+      }
+    };
+    self.apply_config(
+      &contents,
+      &format!("`{}'", path.display()),
+      path.parent().unwrap_or_else(|| Path::new("/")),
+    )
+  }
+
+  /// Apply `contents`, which is in the format of `nix.conf` and came from
+  /// `origin`. Files it includes are relative to `dir`.
+  pub fn apply_config(&mut self, contents: &str, origin: &str, dir: &Path) -> Result<()> {
+    for line in contents.lines() {
+      let line = line.split('#').next().unwrap_or_default();
+      let tokens = line.split_whitespace().collect::<Vec<_>>();
+      match tokens.as_slice() {
+        [] => {}
+        [include @ ("include" | "!include"), file] => {
+          self.read_file(&dir.join(file), *include == "include")?;
+        }
+        [name, "=", value @ ..] => self.set(name, &value.join(" "), origin)?,
+        _ => bail!(Error::Syntax {
+          line: line.trim().into(),
+          origin: origin.into(),
+        }),
+      }
+    }
+    Ok(())
+  }
+
+  /// Set the setting `name` to `value`, which came from `origin`.
+  pub fn set(&mut self, name: &str, value: &str, origin: &str) -> Result<()> {
+    let invalid = || Error::InvalidValue {
+      name: name.into(),
+      value: value.into(),
+      origin: origin.into(),
+    };
+    match name {
+      "min-free" => self.min_free = parse_size(value).ok_or_else(invalid)?,
+      "max-free" => self.max_free = parse_size(value).ok_or_else(invalid)?,
+      "min-free-check-interval" => {
+        self.min_free_check_interval = value.parse().map_err(|_| invalid())?
+      }
+      "auto-optimise-store" => self.auto_optimise_store = parse_bool(value).ok_or_else(invalid)?,
+      "keep-outputs" => self.keep_outputs = parse_bool(value).ok_or_else(invalid)?,
+      "keep-derivations" => self.keep_derivations = parse_bool(value).ok_or_else(invalid)?,
+      "gc-reserved-space" => self.gc_reserved_space = parse_size(value).ok_or_else(invalid)?,
+      "fsync-metadata" => self.fsync_metadata = parse_bool(value).ok_or_else(invalid)?,
+      "sqlite-busy-timeout" => self.sqlite_busy_timeout = value.parse().map_err(|_| invalid())?,
+      "sqlite-wal-autocheckpoint" => {
+        self.sqlite_wal_autocheckpoint = value.parse().map_err(|_| invalid())?
+      }
+      "path-info-cache-size" => self.path_info_cache_size = value.parse().map_err(|_| invalid())?,
+      _ => bail!(Error::UnknownSetting {
+        name: name.into(),
+        origin: origin.into(),
+      }),
+    }
+    Ok(())
+  }
+
+  /// Where the store is, as it sees itself and on this machine, which is
+  /// the same place.
+  pub fn dirs(&self) -> Dirs {
+    Dirs {
+      root: "/".into(),
+      store_dir: self.store_dir.clone(),
+      state_dir: self.state_dir.clone(),
+      log_dir: self.log_dir.clone(),
+    }
+  }
+
+  pub fn db_config(&self) -> DbConfig {
+    DbConfig {
+      busy_timeout: Duration::from_secs(self.sqlite_busy_timeout),
+      wal_autocheckpoint: self.sqlite_wal_autocheckpoint,
+      synchronous: if self.fsync_metadata {
+        Synchronous::Normal
+      } else {
+        Synchronous::Off
+      },
+      ..Default::default()
+    }
+  }
+
+  pub fn auto_gc_settings(&self) -> AutoGcSettings {
+    AutoGcSettings {
+      min_free: self.min_free,
+      max_free: self.max_free,
+      check_interval: Duration::from_secs(self.min_free_check_interval),
+    }
+  }
+
+  /// The options for collecting all the garbage.
+  pub fn gc_options(&self) -> GcOptions {
+    GcOptions {
+      keep_outputs: self.keep_outputs,
+      keep_derivations: self.keep_derivations,
+      ..Default::default()
+    }
+  }
+}
+
+/// Prints the settings in the format of `nix.conf`, leaving out the
+/// directories, which don't go there.
+impl fmt::Display for Settings {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    writeln!(f, "auto-optimise-store = {}", self.auto_optimise_store)?;
+    writeln!(f, "fsync-metadata = {}", self.fsync_metadata)?;
+    writeln!(f, "gc-reserved-space = {}", self.gc_reserved_space)?;
+    writeln!(f, "keep-derivations = {}", self.keep_derivations)?;
+    writeln!(f, "keep-outputs = {}", self.keep_outputs)?;
+    writeln!(f, "max-free = {}", self.max_free)?;
+    writeln!(f, "min-free = {}", self.min_free)?;
+    writeln!(
+      f,
+      "min-free-check-interval = {}",
+      self.min_free_check_interval
+    )?;
+    writeln!(f, "path-info-cache-size = {}", self.path_info_cache_size)?;
+    writeln!(f, "sqlite-busy-timeout = {}", self.sqlite_busy_timeout)?;
+    writeln!(
+      f,
+      "sqlite-wal-autocheckpoint = {}",
+      self.sqlite_wal_autocheckpoint
+    )
+  }
+}
+
+fn parse_bool(s: &str) -> Option<bool> {
+  match s {
+    "true" => Some(true),
+    "false" => Some(false),
+    _ => None,
+  }
+}
+
+/// A number of bytes, possibly with a `K`, `M`, `G` or `T` suffix.
+fn parse_size(s: &str) -> Option<u64> {
+  let (digits, shift) = match s.chars().last()? {
+    'K' => (&s[..s.len() - 1], 10),
+    'M' => (&s[..s.len() - 1], 20),
+    'G' => (&s[..s.len() - 1], 30),
+    'T' => (&s[..s.len() - 1], 40),
+    _ => (s, 0),
+  };
+  digits.parse::<u64>().ok()?.checked_mul(1 << shift)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::collections::HashMap;
+
+  fn load(env: &[(&str, &Path)]) -> Result<Settings> {
+    let env = env
+      .iter()
+      .map(|(k, v)| (k.to_string(), OsString::from(v)))
+      .collect::<HashMap<_, _>>();
+    Settings::load_from_env(|name| env.get(name).cloned())
+  }
+
+  #[test]
+  fn nix_conf() -> Result<()> {
+    let temp = tempfile::tempdir()?;
+    std::fs::write(
+      temp.path().join("nix.conf"),
+      "# comment\nmin-free = 1G\nkeep-outputs = true # trailing comment\ninclude \
+       extra.conf\n!include missing.conf\n",
+    )?;
+    std::fs::write(
+      temp.path().join("extra.conf"),
+      "max-free = 2048\nmin-free = 5M\n",
+    )?;
+
+    let config = OsString::from("auto-optimise-store = true\nkeep-derivations = false");
+    let env = [
+      ("NIX_CONF_DIR", temp.path()),
+      ("NIX_STORE_DIR", Path::new("/foo/store")),
+      ("NIX_STATE_DIR", Path::new("/foo/state")),
+    ];
+    let settings = load(&env)?;
+    assert_eq!(settings.min_free, 5 << 20);
+    assert_eq!(settings.max_free, 2048);
+    assert!(settings.keep_outputs);
+    assert_eq!(settings.store_dir, Path::new("/foo/store"));
+    assert_eq!(settings.state_dir, Path::new("/foo/state"));
+    assert_eq!(settings.log_dir, Path::new("/nix/var/log/nix"));
+
+    let env = env
+      .iter()
+      .map(|(k, v)| (k.to_string(), OsString::from(v)))
+      .chain(Some(("NIX_CONFIG".to_string(), config)))
+      .collect::<HashMap<_, _>>();
+    let settings = Settings::load_from_env(|name| env.get(name).cloned())?;
+    assert!(settings.auto_optimise_store);
+    assert!(!settings.keep_derivations);
+    assert!(settings.gc_options().keep_outputs);
+
+    // what it prints, it reads back
+    let mut again = Settings {
+      store_dir: settings.store_dir.clone(),
+      state_dir: settings.state_dir.clone(),
+      ..Default::default()
+    };
+    again.apply_config(&settings.to_string(), "test", temp.path())?;
+    assert_eq!(again, settings);
+
+    Ok(())
+  }
+
+  #[test]
+  fn errors() -> Result<()> {
+    let temp = tempfile::tempdir()?;
+    let conf = temp.path().join("nix.conf");
+    let err = |contents: &str| -> Error {
+      std::fs::write(&conf, contents).unwrap();
+      let e = load(&[("NIX_CONF_DIR", temp.path())]).unwrap_err();
+      match e.downcast::<Error>() {
+        Ok(e) => e,
+        Err(e) => panic!("unexpected error: {:#}", e),
+      }
+    };
+    assert_matches::assert_matches!(err("no-such-thing = 1"), Error::UnknownSetting { name, .. } if name == "no-such-thing");
+    assert_matches::assert_matches!(err("min-free = lots"), Error::InvalidValue { .. });
+    assert_matches::assert_matches!(err("keep-outputs = 1"), Error::InvalidValue { .. });
+    assert_matches::assert_matches!(err("keep-outputs true"), Error::Syntax { .. });
+
+    // a missing file that has to be there is an error too
+    std::fs::write(&conf, "include missing.conf")?;
+    assert!(load(&[("NIX_CONF_DIR", temp.path())]).is_err());
+
+    Ok(())
+  }
+}
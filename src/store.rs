@@ -1,5 +1,6 @@
 use crate::{
   archive::{ArchiveSink, PathFilter},
+  derivation::Derivation,
   hash::{Encoding, Hash, HashType},
   path::{Path as StorePath, PathSet},
   path_info::{PathInfo, ValidPathInfo},
@@ -12,6 +13,7 @@ use std::{
 };
 
 pub mod cached;
+pub mod error;
 pub mod local;
 
 /// A Nix store, containing a lot of filepaths.
@@ -52,6 +54,12 @@ pub trait Store: Send + Sync {
     format!("{}/{}", self.store_path().display(), p)
   }
 
+  /// Where the contents of `p` are on this machine. That's where its name
+  /// says, unless the store lives somewhere else than its store directory.
+  fn to_real_path(&self, p: &StorePath) -> PathBuf {
+    self.print_store_path(p).into()
+  }
+
   fn make_store_path(&self, path_type: &str, hash: &Hash, name: &str) -> Result<StorePath> {
     let ident = format!(
       "{}:{}:{}:{}",
@@ -182,20 +190,92 @@ pub trait Store: Send + Sync {
     ))
   }
 
+  /// Verify that the contents of `path` match the hash `specified` by a
+  /// fixed-output derivation, ingesting it flat or recursively according to
+  /// `recursive`. Returns the store path the contents belong at.
+  async fn check_fixed_output(
+    &self,
+    name: &str,
+    path: &Path,
+    recursive: bool,
+    specified: &Hash,
+  ) -> Result<StorePath> {
+    let (store_path, actual) = if recursive {
+      self
+        .store_path_for_dir(name, path, specified.type_(), PathFilter::always())
+        .await?
+    } else {
+      self
+        .store_path_for_file(name, path, specified.type_())
+        .await?
+    };
+    if actual != *specified {
+      bail!(error::Error::FixedOutputHashMismatch {
+        path: path.into(),
+        specified: specified.clone(),
+        actual,
+      });
+    }
+    Ok(store_path)
+  }
+
   /// Get info about a valid path. If this method returns `None`, the path is
   /// known not to exist in the store.
   async fn get_path_info(&self, path: &StorePath) -> Result<Option<Arc<dyn PathInfo>>>;
 
   async fn get_referrers(&self, path: &StorePath) -> Result<PathSet>;
 
+  /// Compute the set of paths reachable from `paths` through references,
+  /// including `paths` themselves.
+  async fn compute_fs_closure(&self, paths: &PathSet) -> Result<PathSet> {
+    let mut closure = PathSet::new();
+    let mut queue = paths.iter().cloned().collect::<Vec<_>>();
+    while let Some(path) = queue.pop() {
+      if closure.contains(&path) {
+        continue;
+      }
+      let info = self
+        .get_path_info(&path)
+        .await?
+        .ok_or_else(|| anyhow!("path `{}' is not valid", self.print_store_path(&path)))?;
+      queue.extend(info.references().iter().cloned());
+      closure.insert(path);
+    }
+    Ok(closure)
+  }
+
   async fn is_valid_path(&self, path: &StorePath) -> Result<bool> {
     self.get_path_info(path).await.map(|x| x.is_some())
   }
 
+  /// Read and parse the derivation stored at `path`.
+  async fn read_derivation(&self, path: &StorePath) -> Result<Derivation> {
+    let contents = tokio::fs::read_to_string(self.to_real_path(path))
+      .await
+      .with_context(|| format!("while reading derivation `{}'", path))?;
+    Derivation::parse(self, &contents)
+  }
+
+  /// Write the NAR serialisation of the valid path `path` to `sink`. By
+  /// default, this reads the path from the filesystem.
+  async fn nar_from_path<W: Sink<Bytes> + Send + Unpin>(
+    &self,
+    path: &StorePath,
+    sink: &mut ArchiveSink<W>,
+  ) -> Result<()>
+  where
+    W::Error: std::error::Error + Send + Sync + 'static,
+  {
+    crate::archive::dump_path(&self.to_real_path(path), sink, &PathFilter::always()).await
+  }
+
+  /// Import the path described by `info` from the NAR `source`. If `repair`
+  /// is set, the contents are replaced even if the path is already valid.
   async fn add_nar_to_store<S: ByteStream + Send + Unpin>(
     &self,
     info: &ValidPathInfo,
     source: S,
+    repair: bool,
   ) -> Result<()>;
 
   async fn add_path_to_store(
@@ -1,13 +1,15 @@
 use super::ByteStream;
 use crate::{
-  archive::PathFilter,
+  archive::{ArchiveSink, PathFilter},
   path::Path as StorePath,
   path_info::{PathInfo, ValidPathInfo},
+  settings::Settings,
   Store,
 };
 use anyhow::Result;
+use bytes::Bytes;
 use disk::{CacheEntry, DiskCache};
-use futures::lock::Mutex;
+use futures::{lock::Mutex, Sink};
 use lru_cache::LruCache;
 use std::{
   borrow::Cow,
@@ -26,10 +28,10 @@ pub struct Cached<S> {
 }
 
 impl<S> Cached<S> {
-  pub async fn new(store: S, use_disk_cache: bool) -> Result<Self> {
+  pub async fn new(store: S, use_disk_cache: bool, settings: &Settings) -> Result<Self> {
     Ok(Self {
       store,
-      cache: Mutex::new(LruCache::new(8192)),
+      cache: Mutex::new(LruCache::new(settings.path_info_cache_size)),
       disk_cache: if use_disk_cache {
         Some(DiskCache::open().await?)
       } else {
@@ -49,6 +51,10 @@ impl<S: Store> Store for Cached<S> {
     self.store.get_uri()
   }
 
+  fn to_real_path(&self, p: &StorePath) -> PathBuf {
+    self.store.to_real_path(p)
+  }
+
   async fn get_path_info(&self, path: &StorePath) -> Result<Option<Arc<dyn PathInfo>>> {
     let mut cache = self.cache.lock().await;
     let path_key = self.print_store_path(path);
@@ -90,12 +96,24 @@ impl<S: Store> Store for Cached<S> {
     self.store.add_temp_root(path).await
   }
 
+  async fn nar_from_path<W: Sink<Bytes> + Send + Unpin>(
+    &self,
+    path: &StorePath,
+    sink: &mut ArchiveSink<W>,
+  ) -> Result<()>
+  where
+    W::Error: std::error::Error + Send + Sync + 'static,
+  {
+    self.store.nar_from_path(path, sink).await
+  }
+
   async fn add_nar_to_store<I: ByteStream + Send + Unpin>(
     &self,
     info: &ValidPathInfo,
     source: I,
+    repair: bool,
   ) -> Result<()> {
-    self.store.add_nar_to_store(info, source).await
+    self.store.add_nar_to_store(info, source, repair).await
   }
 
   async fn add_path_to_store(
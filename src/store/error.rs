@@ -0,0 +1,47 @@
+use crate::{
+  hash::{Encoding, Hash},
+  prelude::*,
+};
+
+#[derive(Debug, Error)]
+pub enum Error {
+  #[error(
+    "hash mismatch in fixed-output path `{}':\n  specified: {} ({})\n  got:       {} ({})",
+    path.display(),
+    specified.encode(Encoding::SRI),
+    specified.encode_with_type(Encoding::Base32),
+    actual.encode(Encoding::SRI),
+    actual.encode_with_type(Encoding::Base32)
+  )]
+  FixedOutputHashMismatch {
+    path: PathBuf,
+    specified: Hash,
+    actual: Hash,
+  },
+  #[error(
+    "output `{}' is not allowed to refer to path `{}' (forbidden by `{attr}')\n  reference chain: {}",
+    output.display(),
+    path.display(),
+    itertools::join(chain.iter().map(|p| p.display()), " -> ")
+  )]
+  ReferenceNotAllowed {
+    output: PathBuf,
+    path: PathBuf,
+    /// The references leading from `output` to `path`, both inclusive.
+    chain: Vec<PathBuf>,
+    attr: &'static str,
+  },
+  #[error(
+    "derivation output `{}' is not deterministic; rebuilt output kept at `{}'\n  differing files:\n    {}",
+    path.display(),
+    check_path.display(),
+    itertools::join(differing.iter().map(|p| p.display()), "\n    ")
+  )]
+  NotDeterministic {
+    path: PathBuf,
+    check_path: PathBuf,
+    differing: Vec<PathBuf>,
+  },
+  #[error("derivation contains an illegal reference specifier `{spec}' in `{attr}'")]
+  IllegalReferenceSpecifier { spec: String, attr: &'static str },
+}
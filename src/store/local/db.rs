@@ -1,15 +1,19 @@
+use super::error::Error;
 use crate::{
+  derivation::Derivation,
   hash::{Encoding, Hash},
   path::{Path as StorePath, PathSet},
   path_info::ValidPathInfo,
   prelude::*,
-  Store,
 };
-use rusqlite::{Connection, DatabaseName};
+use nix::fcntl::{self, FlockArg};
+use rusqlite::{Connection, DatabaseName, OpenFlags};
 use std::{
-  collections::BTreeSet,
+  collections::{BTreeMap, BTreeSet},
   convert::TryInto,
-  path::Path,
+  os::unix::io::AsRawFd,
+  path::{Path, PathBuf},
+  sync::{Arc, Condvar, Mutex},
   time::{Duration, SystemTime},
 };
 
@@ -26,116 +30,817 @@ pub static REGISTER_VALID_PATHS: &str =
   "insert into ValidPaths (path, hash, registrationTime, deriver, narSize, ultimate, sigs, ca) \
    values (:path, :hash, :registrationTime, :deriver, :narSize, :ultimate, :sigs, :ca)";
 
-#[derive(derive_more::Deref, derive_more::DerefMut)]
-pub struct Db(Connection);
+static QUERY_VALID_PATHS: &str = "select path from ValidPaths";
+
+static QUERY_VALID_PATH: &str = "select 1 from ValidPaths where path = ?";
+
+static INVALIDATE_PATH: &str = "delete from ValidPaths where path = :path";
+
+static DELETE_REFERENCES_FROM: &str =
+  "delete from Refs where referrer = (select id from ValidPaths where path = :path)";
+
+static ADD_REFERENCE: &str = "insert or replace into Refs (referrer, reference) values \
+                              (:referrer, (select id from ValidPaths where path = :reference))";
+
+static ADD_DERIVATION_OUTPUT: &str =
+  "insert or replace into DerivationOutputs (drv, id, path) values (:drv, :id, :path)";
+
+static QUERY_DERIVATION_OUTPUTS: &str =
+  "select path from DerivationOutputs where drv = (select id from ValidPaths where path = :path)";
+
+/// The version of the schema that this code works with.
+pub const SCHEMA_VERSION: i64 = 10;
+
+/// The oldest schema version that can still be upgraded. Nix dropped the
+/// ones before it long ago.
+pub const OLDEST_SCHEMA_VERSION: i64 = 7;
+
+/// The changes that bring the database up to each schema version from the
+/// one before, in order. A database without a version is created from
+/// `schema.sql` instead.
+static MIGRATIONS: &[(i64, &str)] = &[
+  (
+    8,
+    "alter table ValidPaths add column ultimate integer;
+     alter table ValidPaths add column sigs text;",
+  ),
+  (9, "drop table if exists FailedPaths;"),
+  (10, "alter table ValidPaths add column ca text;"),
+];
+
+/// SQLite's `synchronous` setting.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Synchronous {
+  Off,
+  Normal,
+  Full,
+  Extra,
+}
+
+impl Synchronous {
+  fn as_str(self) -> &'static str {
+    match self {
+      Self::Off => "off",
+      Self::Normal => "normal",
+      Self::Full => "full",
+      Self::Extra => "extra",
+    }
+  }
+}
+
+/// How to set up connections to the database.
+#[derive(Clone, Debug)]
+pub struct DbConfig {
+  /// How long to wait for another process to release a lock on the
+  /// database.
+  pub busy_timeout: Duration,
+  /// The number of WAL pages after which the WAL is checkpointed.
+  pub wal_autocheckpoint: i64,
+  pub synchronous: Synchronous,
+  /// How many read connections may be open at once.
+  pub max_readers: usize,
+  /// When the database is opened read-only, promise SQLite that nobody
+  /// changes it either, so that it can be read without write access to the
+  /// directory it's in. Don't use this on a store that's in use.
+  pub immutable: bool,
+}
+
+impl Default for DbConfig {
+  fn default() -> Self {
+    Self {
+      busy_timeout: Duration::from_secs(60 * 60),
+      wal_autocheckpoint: 40000,
+      synchronous: Synchronous::Normal,
+      max_readers: 8,
+      immutable: false,
+    }
+  }
+}
+
+/// The store's database. Queries run on the blocking thread pool rather
+/// than on the executor. Since the database is in WAL mode, readers don't
+/// get in each other's way, or a writer's: each one takes a connection of
+/// its own from a pool. Writes are serialised through a single connection.
+/// Every connection keeps its prepared statements around.
+#[derive(Clone)]
+pub struct Db(Arc<Inner>);
+
+struct Inner {
+  path: PathBuf,
+  store_dir: PathBuf,
+  config: DbConfig,
+  read_only: bool,
+  writer: Mutex<Connection>,
+  readers: Mutex<Readers>,
+  /// Signalled whenever a read connection is returned to the pool.
+  reader_returned: Condvar,
+}
+
+#[derive(Default)]
+struct Readers {
+  /// The read connections that aren't in use right now.
+  idle: Vec<Connection>,
+  /// How many read connections there are, whether in use or not.
+  open: usize,
+}
+
+impl Inner {
+  fn print(&self, path: &StorePath) -> String {
+    format!("{}/{}", self.store_dir.display(), path)
+  }
+
+  fn parse(&self, path: &str) -> Result<StorePath> {
+    StorePath::new(Path::new(path), &self.store_dir)
+  }
+
+  /// Take a read connection from the pool, opening a new one if there's
+  /// still room, and otherwise waiting for one to come back.
+  fn take_reader(&self) -> Result<Connection> {
+    let mut readers = self.readers.lock().unwrap();
+    loop {
+      if let Some(conn) = readers.idle.pop() {
+        return Ok(conn);
+      }
+      if readers.open < self.config.max_readers.max(1) {
+        readers.open += 1;
+        drop(readers);
+        return connect(&self.path, &self.config, self.read_only).inspect_err(|_| {
+          self.readers.lock().unwrap().open -= 1;
+          self.reader_returned.notify_one();
+        });
+      }
+      readers = self.reader_returned.wait(readers).unwrap();
+    }
+  }
+
+  fn return_reader(&self, conn: Connection) {
+    self.readers.lock().unwrap().idle.push(conn);
+    self.reader_returned.notify_one();
+  }
+}
+
+fn connect(path: &Path, config: &DbConfig, read_only: bool) -> Result<Connection> {
+  debug!("opening connection to sqlite DB at {}", path.display());
+  let mut conn = if read_only {
+    let flags = OpenFlags::SQLITE_OPEN_READ_ONLY
+      | OpenFlags::SQLITE_OPEN_URI
+      | OpenFlags::SQLITE_OPEN_NO_MUTEX;
+    if config.immutable {
+      Connection::open_with_flags(immutable_uri(path)?, flags)?
+    } else {
+      Connection::open_with_flags(path, flags)?
+    }
+  } else {
+    Connection::open(path)?
+  };
+  if log_enabled!(log::Level::Trace) {
+    conn.trace(Some(|x| trace!("{}", x)));
+  }
+  conn.busy_timeout(config.busy_timeout)?;
+  conn.pragma_update(None, "foreign_keys", &1u8)?;
+  conn.pragma_update(None, "synchronous", &config.synchronous.as_str())?;
+  Ok(conn)
+}
+
+/// A URI for the database at `path` that tells SQLite it never changes.
+fn immutable_uri(path: &Path) -> Result<String> {
+  let path = path
+    .to_str()
+    .ok_or_else(|| anyhow!("database path `{}' isn't valid UTF-8", path.display()))?;
+  let mut uri = String::from("file:");
+  for c in path.chars() {
+    match c {
+      '%' | '?' | '#' => uri.push_str(&format!("%{:02X}", c as u32)),
+      c => uri.push(c),
+    }
+  }
+  uri.push_str("?immutable=1");
+  Ok(uri)
+}
 
 impl Db {
-  pub fn open(path: &Path, create: bool) -> Result<Self> {
-    debug!("opening connection to sqlite DB at {}", path.display());
-    let mut conn = Connection::open(path)?;
-    if log_enabled!(log::Level::Trace) {
-      conn.trace(Some(|x| trace!("{}", x)));
+  /// Open the database at `path`, which describes the paths in `store_dir`.
+  ///
+  /// A read-only database is used as it is: nothing is written to it, not
+  /// even to switch it over to WAL mode.
+  pub fn open(path: &Path, store_dir: &Path, config: &DbConfig, read_only: bool) -> Result<Self> {
+    let conn = connect(path, config, read_only)?;
+    if !read_only {
+      let cur_mode = conn.pragma_query_value(Some(DatabaseName::Main), "journal_mode", |r| {
+        r.get::<_, String>(0)
+      })?;
+      let new_mode = "wal";
+      if cur_mode != new_mode {
+        conn.pragma_update(Some(DatabaseName::Main), "journal_mode", &new_mode)?;
+      }
+      if new_mode == "wal" {
+        conn.pragma_update(None, "wal_autocheckpoint", &config.wal_autocheckpoint)?;
+      }
+    }
+    Ok(Self(Arc::new(Inner {
+      path: path.into(),
+      store_dir: store_dir.into(),
+      config: config.clone(),
+      read_only,
+      writer: Mutex::new(conn),
+      readers: Default::default(),
+      reader_returned: Condvar::new(),
+    })))
+  }
+
+  /// The schema version of the database. Its `user_version` is what
+  /// counts, but databases that predate it may still have their version in
+  /// `schema_file`.
+  fn schema_version(conn: &Connection, schema_file: &Path) -> Result<i64> {
+    let version = conn.pragma_query_value(None, "user_version", |r| r.get::<_, i64>(0))?;
+    if version != 0 {
+      return Ok(version);
     }
-    conn.busy_timeout(Duration::from_millis(60 * 60 * 1000))?;
-    conn.pragma_update(None, "foreign_keys", &1u8)?;
-    conn.pragma_update(None, "synchronous", &"normal")?;
-    let cur_mode = conn.pragma_query_value(Some(DatabaseName::Main), "journal_mode", |r| {
-      r.get::<_, String>(0)
-    })?;
-    let new_mode = "wal";
-    if cur_mode != new_mode {
-      conn.pragma_update(Some(DatabaseName::Main), "journal_mode", &new_mode)?;
+    match std::fs::read_to_string(schema_file) {
+      Ok(s) => s
+        .trim()
+        .parse()
+        .with_context(|| format!("invalid schema file `{}'", schema_file.display())),
+      Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(0),
+      Err(e) => Err(e.into()),
     }
-    if new_mode == "wal" {
-      conn.pragma_update(None, "wal_autocheckpoint", &40000i64)?;
+  }
+
+  /// Fail if the database is newer than this code, or too old to upgrade.
+  /// Returns whether it's older.
+  fn check_schema(&self, conn: &Connection, schema_file: &Path) -> Result<bool> {
+    let version = Self::schema_version(conn, schema_file)?;
+    if version > SCHEMA_VERSION {
+      bail!(Error::SchemaTooNew {
+        path: self.0.path.clone(),
+        version,
+        supported: SCHEMA_VERSION,
+      });
     }
-    if create {
-      conn.execute_batch(include_str!("schema.sql"))?;
+    if version != 0 && version < OLDEST_SCHEMA_VERSION {
+      bail!(Error::SchemaTooOld {
+        path: self.0.path.clone(),
+        version,
+        oldest: OLDEST_SCHEMA_VERSION,
+      });
     }
-    Ok(Self(conn))
+    Ok(version < SCHEMA_VERSION)
   }
 
-  pub fn get_path_info<S: Store>(
-    &self,
-    store: &S,
-    path: &StorePath,
-  ) -> Result<Option<ValidPathInfo>> {
-    let canon = store.print_store_path(path);
-    let mut stmt0 = self.prepare(QUERY_PATH_INFO)?;
-
-    let mut mvalid = stmt0.query_and_then_named(
-      named_params! {":path": canon.as_str()},
-      |row| -> Result<ValidPathInfo> {
-        let mderiver: Option<String> = row.get("deriver")?;
-        Ok(ValidPathInfo {
-          id: row.get::<_, i64>("id")?.try_into()?,
-          store_path: path.clone(),
-          deriver: mderiver
-            .map(|x| store.parse_store_path(Path::new(&x)))
-            .transpose()?,
-          nar_hash: Hash::decode(&row.get::<_, String>("hash")?)?,
-          references: PathSet::new(),
-          registration_time: SystemTime::UNIX_EPOCH
-            + Duration::from_secs(row.get::<_, i64>("registrationTime")?.try_into()?),
-          nar_size: Some(row.get::<_, i64>("narSize")?.try_into()?),
-          signatures: row
-            .get::<_, Option<String>>("sigs")?
-            .map_or(BTreeSet::new(), |s| {
-              s.split(' ').map(|x| x.to_string()).collect::<BTreeSet<_>>()
-            }),
-          content_addressed: row.get("ca")?,
-          ultimate: row.get::<_, bool>("ultimate")?,
-        })
-      },
-    )?;
+  /// Check that the database can be used as it is, without upgrading its
+  /// schema.
+  pub fn require_schema(&self, schema_file: &Path) -> Result<()> {
+    let conn = self.0.writer.lock().unwrap();
+    if self.check_schema(&conn, schema_file)? {
+      bail!(
+        "the database at `{}' has an old schema and needs to be upgraded",
+        self.0.path.display()
+      );
+    }
+    Ok(())
+  }
 
-    if let Some(mut pinfo) = mvalid.next().transpose()? {
-      pinfo.references = self
-        .prepare(QUERY_REFERENCES)?
-        .query_and_then_named(named_params! {":referrer": pinfo.id as i64}, |row| {
-          Ok(store.parse_store_path(Path::new(&row.get::<_, String>(0)?))?)
-        })?
-        .collect::<Result<_>>()?;
+  /// Bring the schema up to `SCHEMA_VERSION`, running the migrations it
+  /// needs in a single transaction while holding `gc_lock`, and record the
+  /// new version in `schema_file` as well. Fails if the database is newer
+  /// than this code.
+  pub fn upgrade_schema(&self, schema_file: &Path, gc_lock: &Path) -> Result<()> {
+    let mut conn = self.0.writer.lock().unwrap();
+    if !self.check_schema(&conn, schema_file)? {
+      return Ok(());
+    }
+
+    debug!("acquiring global GC lock at `{}'", gc_lock.display());
+    let lock = std::fs::File::create(gc_lock)?;
+    fcntl::flock(lock.as_raw_fd(), FlockArg::LockExclusive)?;
+    // someone else may have done it while we waited
+    if !self.check_schema(&conn, schema_file)? {
+      return Ok(());
+    }
 
-      Ok(Some(pinfo))
+    let version = Self::schema_version(&conn, schema_file)?;
+    info!(
+      "upgrading the database schema from version {} to {}",
+      version, SCHEMA_VERSION
+    );
+    let txn = conn.transaction()?;
+    if version == 0 {
+      txn.execute_batch(include_str!("schema.sql"))?;
     } else {
-      Ok(None)
+      for (_, sql) in MIGRATIONS.iter().filter(|(v, _)| *v > version) {
+        txn.execute_batch(sql)?;
+      }
     }
+    txn.pragma_update(None, "user_version", &SCHEMA_VERSION)?;
+    txn.commit()?;
+    std::fs::write(schema_file, SCHEMA_VERSION.to_string())?;
+    Ok(())
+  }
+
+  /// Whether `path` is valid, for when the store is being opened and
+  /// there's no executor to keep free yet.
+  pub fn is_valid_path_blocking(&self, path: &StorePath) -> Result<bool> {
+    let conn = self.0.take_reader()?;
+    let res = conn
+      .prepare_cached(QUERY_VALID_PATH)
+      .and_then(|mut stmt| stmt.exists(&[self.0.print(path)]));
+    self.0.return_reader(conn);
+    Ok(res?)
   }
 
-  pub fn get_referrers<S: Store>(&self, store: &S, path: &StorePath) -> Result<PathSet> {
+  /// Run `f` with a read connection on the blocking thread pool.
+  async fn read<T, F>(&self, f: F) -> Result<T>
+  where
+    T: Send + 'static,
+    F: FnOnce(&Inner, &Connection) -> Result<T> + Send + 'static,
+  {
+    let inner = self.0.clone();
+    tokio::task::spawn_blocking(move || {
+      let conn = inner.take_reader()?;
+      let res = f(&inner, &conn);
+      inner.return_reader(conn);
+      res
+    })
+    .await?
+  }
+
+  /// Run `f` with the write connection on the blocking thread pool, once
+  /// nobody else is writing.
+  async fn write<T, F>(&self, f: F) -> Result<T>
+  where
+    T: Send + 'static,
+    F: FnOnce(&Inner, &mut Connection) -> Result<T> + Send + 'static,
+  {
+    let inner = self.0.clone();
+    tokio::task::spawn_blocking(move || {
+      let mut conn = inner.writer.lock().unwrap();
+      f(&inner, &mut conn)
+    })
+    .await?
+  }
+
+  pub async fn get_path_info(&self, path: &StorePath) -> Result<Option<ValidPathInfo>> {
+    let path = path.clone();
+    self
+      .read(move |db, conn| {
+        let mut stmt0 = conn.prepare_cached(QUERY_PATH_INFO)?;
+
+        let mut mvalid = stmt0.query_and_then_named(
+          named_params! {":path": db.print(&path)},
+          |row| -> Result<ValidPathInfo> {
+            let mderiver: Option<String> = row.get("deriver")?;
+            Ok(ValidPathInfo {
+              id: row.get::<_, i64>("id")?.try_into()?,
+              store_path: path.clone(),
+              deriver: mderiver.map(|x| db.parse(&x)).transpose()?,
+              nar_hash: Hash::decode(&row.get::<_, String>("hash")?)?,
+              references: PathSet::new(),
+              registration_time: SystemTime::UNIX_EPOCH
+                + Duration::from_secs(row.get::<_, i64>("registrationTime")?.try_into()?),
+              nar_size: row
+                .get::<_, Option<i64>>("narSize")?
+                .map(TryInto::try_into)
+                .transpose()?,
+              signatures: row
+                .get::<_, Option<String>>("sigs")?
+                .map_or(BTreeSet::new(), |s| {
+                  s.split(' ')
+                    .filter(|x| !x.is_empty())
+                    .map(|x| x.to_string())
+                    .collect::<BTreeSet<_>>()
+                }),
+              // Nix has been known to write empty strings rather than nulls
+              content_addressed: row
+                .get::<_, Option<String>>("ca")?
+                .filter(|ca| !ca.is_empty()),
+              ultimate: row.get::<_, Option<bool>>("ultimate")?.unwrap_or(false),
+            })
+          },
+        )?;
+
+        if let Some(mut pinfo) = mvalid.next().transpose()? {
+          pinfo.references = conn
+            .prepare_cached(QUERY_REFERENCES)?
+            .query_and_then_named(named_params! {":referrer": pinfo.id as i64}, |row| {
+              db.parse(&row.get::<_, String>(0)?)
+            })?
+            .collect::<Result<_>>()?;
+
+          Ok(Some(pinfo))
+        } else {
+          Ok(None)
+        }
+      })
+      .await
+  }
+
+  pub async fn get_referrers(&self, path: &StorePath) -> Result<PathSet> {
+    let path = path.clone();
+    self
+      .read(move |db, conn| {
+        conn
+          .prepare_cached(QUERY_REFERRERS)?
+          .query_and_then(&[db.print(&path).as_str()], |row| {
+            db.parse(&row.get::<_, String>(0)?)
+          })?
+          .collect::<Result<_>>()
+      })
+      .await
+  }
+
+  pub async fn get_valid_paths(&self) -> Result<PathSet> {
+    self
+      .read(|db, conn| {
+        conn
+          .prepare_cached(QUERY_VALID_PATHS)?
+          .query_and_then(rusqlite::NO_PARAMS, |row| {
+            db.parse(&row.get::<_, String>(0)?)
+          })?
+          .collect::<Result<_>>()
+      })
+      .await
+  }
+
+  /// The outputs of the derivation `drv_path`, whether they're valid or not.
+  pub async fn get_derivation_outputs(&self, drv_path: &StorePath) -> Result<PathSet> {
+    let drv_path = drv_path.clone();
+    self
+      .read(move |db, conn| {
+        conn
+          .prepare_cached(QUERY_DERIVATION_OUTPUTS)?
+          .query_and_then(&[db.print(&drv_path).as_str()], |row| {
+            db.parse(&row.get::<_, String>(0)?)
+          })?
+          .collect::<Result<_>>()
+      })
+      .await
+  }
+
+  /// Remove `paths`, which may refer to each other, from the database in a
+  /// single transaction. Fails if any other valid path still refers to one
+  /// of them.
+  pub async fn invalidate_paths(&self, paths: Vec<StorePath>) -> Result<()> {
     self
-      .prepare(QUERY_REFERRERS)?
-      .query_and_then(&[store.print_store_path(path).as_str()], |row| {
-        Ok(store.parse_store_path(Path::new(&row.get::<_, String>(0)?))?)
-      })?
-      .collect::<Result<_>>()
+      .write(move |db, conn| {
+        let txn = conn.transaction()?;
+        // references among `paths` would keep them from being deleted
+        for path in &paths {
+          txn
+            .prepare_cached(DELETE_REFERENCES_FROM)?
+            .execute_named(named_params! {":path": db.print(path)})?;
+        }
+        for path in &paths {
+          debug!("invalidating path `{}'", path);
+          txn
+            .prepare_cached(INVALIDATE_PATH)?
+            .execute_named(named_params! {":path": db.print(path)})?;
+        }
+        txn.commit()?;
+        Ok(())
+      })
+      .await
   }
 
-  pub fn insert_valid_paths<'a, S: Store, I: IntoIterator<Item = &'a ValidPathInfo>>(
-    &mut self,
-    store: &S,
-    paths: I,
+  /// Register `paths` as valid in a single transaction. `drvs` has the
+  /// contents of the derivations among them, whose outputs get recorded
+  /// too.
+  pub async fn insert_valid_paths(
+    &self,
+    paths: Vec<ValidPathInfo>,
+    drvs: BTreeMap<StorePath, Derivation>,
   ) -> Result<()> {
-    let txn = self.transaction()?;
-    for path in paths.into_iter() {
-      txn.execute_named(
-        REGISTER_VALID_PATHS,
-        named_params! {
-          ":path": store.print_store_path(&path.store_path),
-          ":hash": path.nar_hash.encode_with_type(Encoding::Base16),
-          ":registrationTime": path.registration_time.duration_since(SystemTime::UNIX_EPOCH)?.as_secs() as i64,
-          ":deriver": path.deriver.as_ref().map(|r|store.print_store_path(r)),
-          ":narSize": path.nar_size.unwrap_or(0) as i64,
-          ":ultimate": path.ultimate,
-          ":sigs": itertools::join(&path.signatures, " "),
-          ":ca": ""
-        },
-      )?;
-      let row_id = txn.last_insert_rowid();
-      debug!("inserted new row: {:?}", row_id);
-    }
-    txn.commit()?;
+    self
+      .write(move |db, conn| {
+        let txn = conn.transaction()?;
+        let mut ids = vec![];
+        for path in &paths {
+          txn.prepare_cached(REGISTER_VALID_PATHS)?.execute_named(
+            named_params! {
+              ":path": db.print(&path.store_path),
+              ":hash": path.nar_hash.encode_with_type(Encoding::Base16),
+              ":registrationTime": path.registration_time.duration_since(SystemTime::UNIX_EPOCH)?.as_secs() as i64,
+              ":deriver": path.deriver.as_ref().map(|r| db.print(r)),
+              ":narSize": path.nar_size.map(|s| s as i64),
+              ":ultimate": path.ultimate,
+              ":sigs": Some(itertools::join(&path.signatures, " ")).filter(|s| !s.is_empty()),
+              ":ca": path.content_addressed.as_deref()
+            },
+          )?;
+          let row_id = txn.last_insert_rowid();
+          debug!("inserted new row: {:?}", row_id);
+          ids.push((row_id, path));
+        }
+        for (row_id, path) in ids {
+          if let Some(drv) = drvs.get(&path.store_path) {
+            for (id, out) in &drv.outputs {
+              txn.prepare_cached(ADD_DERIVATION_OUTPUT)?.execute_named(
+                named_params! {
+                  ":drv": row_id,
+                  ":id": id,
+                  ":path": db.print(&out.path),
+                },
+              )?;
+            }
+          }
+          for reference in &path.references {
+            txn
+              .prepare_cached(ADD_REFERENCE)?
+              .execute_named(
+                named_params! {
+                  ":referrer": row_id,
+                  ":reference": db.print(reference),
+                },
+              )
+              .with_context(|| {
+                format!(
+                  "while registering reference from `{}' to `{}'",
+                  path.store_path, reference
+                )
+              })?;
+          }
+        }
+        txn.commit()?;
+        Ok(())
+      })
+      .await
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{super::LocalStore, *};
+  use crate::{archive::PathFilter, hash::HashType, Store};
+  use tokio::time::{delay_for, timeout};
+
+  #[test]
+  fn read_while_writing() -> Result<()> {
+    crate::util::run_test(async {
+      let temp = tempfile::tempdir()?;
+      let store = LocalStore::open(temp.path())?;
+      let src = temp.path().join("src");
+      tokio::fs::write(&src, "foo").await?;
+      let path = store
+        .add_path_to_store("foo", &src, HashType::SHA256, PathFilter::always(), false)
+        .await?;
+
+      // another process is in the middle of a write
+      let other = Connection::open(store.dirs.db_file())?;
+      other.execute_batch("begin immediate")?;
+
+      // our own write has to wait for it, but that holds up neither the
+      // executor nor readers
+      let db = store.db.clone();
+      let invalidated = path.clone();
+      let write = tokio::spawn(async move { db.invalidate_paths(vec![invalidated]).await });
+      delay_for(Duration::from_millis(50)).await;
+      let info = timeout(Duration::from_secs(1), store.db.get_path_info(&path)).await??;
+      assert!(info.is_some());
+
+      other.execute_batch("rollback")?;
+      write.await??;
+      assert!(store.db.get_path_info(&path).await?.is_none());
+
+      Ok(())
+    })
+  }
+
+  #[test]
+  fn config() -> Result<()> {
+    crate::util::run_test(async {
+      let temp = tempfile::tempdir()?;
+      let config = DbConfig {
+        busy_timeout: Duration::from_millis(100),
+        wal_autocheckpoint: 1000,
+        synchronous: Synchronous::Full,
+        max_readers: 2,
+        ..Default::default()
+      };
+      let store = LocalStore::open_with(super::super::dirs::Dirs::new(temp.path()), &config)?;
+      let (sync, checkpoint) = store
+        .db
+        .read(|_, conn| {
+          Ok((
+            conn.pragma_query_value(None, "synchronous", |r| r.get::<_, i64>(0))?,
+            conn.pragma_query_value(None, "wal_autocheckpoint", |r| r.get::<_, i64>(0))?,
+          ))
+        })
+        .await?;
+      assert_eq!(sync, 2);
+      assert_eq!(checkpoint, 1000);
+
+      // with more readers than connections, some wait their turn
+      let reads = (0..8).map(|_| store.db.get_valid_paths());
+      for paths in futures::future::join_all(reads).await {
+        assert!(paths?.is_empty());
+      }
+      {
+        let readers = store.db.0.readers.lock().unwrap();
+        assert!(readers.open <= 2);
+        assert_eq!(readers.idle.len(), readers.open);
+      }
+
+      // the busy timeout bounds how long a write waits for another process
+      let other = Connection::open(store.dirs.db_file())?;
+      other.execute_batch("begin immediate")?;
+      let path = StorePath::from_base_name("00000000000000000000000000000000-foo")?;
+      let res = timeout(
+        Duration::from_secs(5),
+        store.db.invalidate_paths(vec![path.clone()]),
+      )
+      .await?;
+      assert!(res.is_err());
+
+      Ok(())
+    })
+  }
+
+  #[test]
+  fn schema_upgrades() -> Result<()> {
+    let temp = tempfile::tempdir()?;
+    let dirs = super::super::dirs::Dirs::new(temp.path());
+    dirs.create()?;
+    let db_path = dirs.db_file();
+    let schema_file = dirs.schema_file();
+    let user_version =
+      |conn: &Connection| conn.pragma_query_value(None, "user_version", |r| r.get::<_, i64>(0));
+
+    // a database from before there were versions gets upgraded, but only
+    // once the GC lock is free
+    let old = Connection::open(&db_path)?;
+    old.execute_batch(include_str!("schema.sql"))?;
+    let gc_lock = std::fs::File::create(dirs.gc_lock())?;
+    fcntl::flock(gc_lock.as_raw_fd(), FlockArg::LockExclusive)?;
+    let root = temp.path().to_owned();
+    let opening = std::thread::spawn(move || LocalStore::open(&root).map(drop));
+    std::thread::sleep(Duration::from_millis(100));
+    assert_eq!(user_version(&old)?, 0);
+    drop(gc_lock);
+    opening.join().unwrap()?;
+    assert_eq!(user_version(&old)?, SCHEMA_VERSION);
+    assert_eq!(
+      std::fs::read_to_string(&schema_file)?,
+      SCHEMA_VERSION.to_string()
+    );
+
+    // one written by newer code is left alone
+    old.pragma_update(None, "user_version", &(SCHEMA_VERSION + 1))?;
+    let err = LocalStore::open(temp.path()).map(drop).unwrap_err();
+    assert_matches::assert_matches!(
+      err.downcast_ref::<Error>(),
+      Some(Error::SchemaTooNew { version, .. }) if *version == SCHEMA_VERSION + 1
+    );
+
+    // and so is one that only says so in the schema file
+    old.pragma_update(None, "user_version", &0)?;
+    std::fs::write(&schema_file, (SCHEMA_VERSION + 1).to_string())?;
+    assert!(LocalStore::open(temp.path()).is_err());
+
     Ok(())
   }
+
+  #[test]
+  fn schema_migrations() -> Result<()> {
+    let temp = tempfile::tempdir()?;
+    let dirs = super::super::dirs::Dirs::new(temp.path());
+    dirs.create()?;
+    let schema_file = dirs.schema_file();
+
+    // what Nix 1.x left behind
+    let old = Connection::open(dirs.db_file())?;
+    old.execute_batch(
+      "create table ValidPaths (id integer primary key autoincrement not null, path text unique \
+       not null, hash text not null, registrationTime integer not null, deriver text, narSize \
+       integer);
+       create table Refs (referrer integer not null, reference integer not null, primary key \
+       (referrer, reference));
+       create table DerivationOutputs (drv integer not null, id text not null, path text not null, \
+       primary key (drv, id));
+       create table FailedPaths (path text primary key not null, time integer not null);
+       insert into ValidPaths (path, hash, registrationTime) values ('/nix/store/x', 'h', 0);",
+    )?;
+    std::fs::write(&schema_file, "7")?;
+    drop(LocalStore::open(temp.path())?);
+    assert_eq!(
+      std::fs::read_to_string(&schema_file)?,
+      SCHEMA_VERSION.to_string()
+    );
+    let (path, ca) = old.query_row(
+      "select path, ca from ValidPaths",
+      rusqlite::NO_PARAMS,
+      |r| Ok((r.get::<_, String>(0)?, r.get::<_, Option<String>>(1)?)),
+    )?;
+    assert_eq!((path.as_str(), ca), ("/nix/store/x", None));
+    old.prepare("select ultimate, sigs from ValidPaths")?;
+    assert!(old.prepare("select * from FailedPaths").is_err());
+
+    // anything older has to go through Nix first
+    old.pragma_update(None, "user_version", &(OLDEST_SCHEMA_VERSION - 1))?;
+    let err = LocalStore::open(temp.path()).map(drop).unwrap_err();
+    assert_matches::assert_matches!(
+      err.downcast_ref::<Error>(),
+      Some(Error::SchemaTooOld { version, .. }) if *version == OLDEST_SCHEMA_VERSION - 1
+    );
+
+    Ok(())
+  }
+
+  /// Make a store the way Nix would: its database has no `user_version`,
+  /// and leaves out what isn't known rather than storing zeroes.
+  fn nix_fixture(root: &Path) -> Result<(StorePath, StorePath)> {
+    let dirs = super::super::dirs::Dirs::new(root);
+    std::fs::create_dir_all(dirs.real_store_dir())?;
+    std::fs::create_dir_all(dirs.db_dir())?;
+    let a = StorePath::from_base_name("00000000000000000000000000000000-a")?;
+    let b = StorePath::from_base_name("11111111111111111111111111111111-b")?;
+    let store_path = |p: &StorePath| dirs.real_store_dir().join(p.to_string());
+    std::fs::write(store_path(&a), "a")?;
+    std::fs::write(store_path(&b), "b")?;
+    let hash = |s| Hash::hash_str(s, HashType::SHA256).encode_with_type(Encoding::Base16);
+
+    let conn = Connection::open(dirs.db_file())?;
+    conn.execute_batch(include_str!("schema.sql"))?;
+    conn.execute(
+      "insert into ValidPaths (path, hash, registrationTime) values (?, ?, 1)",
+      &[store_path(&a).to_str().unwrap(), &hash("a")],
+    )?;
+    conn.execute(
+      "insert into ValidPaths (path, hash, registrationTime, deriver, narSize, ultimate, sigs, \
+       ca) values (?, ?, 2, ?, 112, 1, 'cache.nixos.org-1:c2ln', '')",
+      &[
+        store_path(&b).to_str().unwrap(),
+        &hash("b"),
+        &format!(
+          "{}/22222222222222222222222222222222-b.drv",
+          dirs.store_dir.display()
+        ),
+      ],
+    )?;
+    conn.execute_batch("insert into Refs (referrer, reference) values (2, 1)")?;
+    std::fs::write(dirs.schema_file(), "10")?;
+    Ok((a, b))
+  }
+
+  #[test]
+  fn nix_database() -> Result<()> {
+    crate::util::run_test(async {
+      let temp = tempfile::tempdir()?;
+      let (a, b) = nix_fixture(temp.path())?;
+      let dirs = super::super::dirs::Dirs::new(temp.path());
+
+      let store = LocalStore::open_read_only(temp.path())?;
+      let info = store.db.get_path_info(&a).await?.unwrap();
+      assert_eq!(info.nar_hash, Hash::hash_str("a", HashType::SHA256));
+      assert_eq!(info.nar_size, None);
+      assert_eq!(info.content_addressed, None);
+      assert!(!info.ultimate);
+      assert!(info.signatures.is_empty());
+      let info = store.db.get_path_info(&b).await?.unwrap();
+      assert_eq!(info.nar_size, Some(112));
+      assert_eq!(info.content_addressed, None);
+      assert!(info.ultimate);
+      assert_eq!(info.references, Some(a.clone()).into_iter().collect());
+      assert_eq!(
+        info.deriver.unwrap().to_string(),
+        "22222222222222222222222222222222-b.drv"
+      );
+      assert_eq!(
+        store.db.get_referrers(&a).await?,
+        Some(b.clone()).into_iter().collect()
+      );
+      drop(store);
+
+      // opening it read-only changed nothing
+      let conn = Connection::open(dirs.db_file())?;
+      assert_eq!(
+        conn.pragma_query_value(None, "user_version", |r| r.get::<_, i64>(0))?,
+        0
+      );
+      assert_eq!(
+        conn.pragma_query_value(None, "journal_mode", |r| r.get::<_, String>(0))?,
+        "delete"
+      );
+      assert!(!dirs.reserved_space().exists());
+      assert!(!dirs.temproots_dir().exists());
+
+      // nor does opening it properly change what's already there
+      let store = LocalStore::open(temp.path())?;
+      assert!(dirs.reserved_space().exists());
+      assert_eq!(std::fs::read_to_string(dirs.schema_file())?, "10");
+      let src = temp.path().join("src");
+      tokio::fs::write(&src, "c").await?;
+      let c = store
+        .add_path_to_store("c", &src, HashType::SHA256, PathFilter::always(), false)
+        .await?;
+      let (hash, ca, sigs): (String, Option<String>, Option<String>) = conn.query_row(
+        "select hash, ca, sigs from ValidPaths where path = ?",
+        &[store.print_store_path(&c)],
+        |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)),
+      )?;
+      assert!(hash.starts_with("sha256:") && hash.len() == 7 + 64);
+      assert_eq!((ca, sigs), (None, None));
+      assert_eq!(store.db.get_valid_paths().await?.len(), 3);
+
+      Ok(())
+    })
+  }
 }
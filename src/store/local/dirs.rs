@@ -4,34 +4,111 @@ use std::{
   path::{Path, PathBuf},
 };
 
-pub struct Dirs(PathBuf);
+/// Where a local store keeps its things. The store, state and log
+/// directories are the ones that paths in the store refer to; on this
+/// machine, they're all under `root`. That way, a store whose paths say
+/// `/nix/store` can be put together somewhere else, like in a chroot.
+#[derive(Clone, Debug)]
+pub struct Dirs {
+  /// `/`, unless the store lives somewhere else than it says.
+  pub root: PathBuf,
+  /// The store directory as store paths know it. Their hashes depend on it.
+  pub store_dir: PathBuf,
+  pub state_dir: PathBuf,
+  pub log_dir: PathBuf,
+}
 
 impl Dirs {
-  pub fn new<P: AsRef<Path>>(root: P) -> Result<Self> {
-    let s = Self(root.as_ref().into());
-    fs::create_dir_all(s.temproots_dir())?;
-    fs::create_dir_all(s.db_dir())?;
-    fs::create_dir_all(s.store_dir())?;
-    Ok(s)
+  /// Everything in `root`, with the store at `<root>/store`, as far as the
+  /// paths in it are concerned too.
+  pub fn new<P: AsRef<Path>>(root: P) -> Self {
+    let root = root.as_ref();
+    Self {
+      root: "/".into(),
+      store_dir: root.join("store"),
+      state_dir: root.join("var/nix"),
+      log_dir: root.join("var/log/nix"),
+    }
+  }
+
+  /// The layout Nix uses, in `root`: the store's paths say `/nix/store`,
+  /// but it's at `<root>/nix/store`.
+  pub fn chroot<P: AsRef<Path>>(root: P) -> Self {
+    Self {
+      root: root.as_ref().into(),
+      store_dir: "/nix/store".into(),
+      state_dir: "/nix/var/nix".into(),
+      log_dir: "/nix/var/log/nix".into(),
+    }
+  }
+
+  /// Create the directories that a writable store needs.
+  pub(super) fn create(&self) -> Result<()> {
+    fs::create_dir_all(self.temproots_dir())?;
+    fs::create_dir_all(self.gcroots_dir())?;
+    fs::create_dir_all(self.db_dir())?;
+    fs::create_dir_all(self.real_store_dir())?;
+    Ok(())
+  }
+
+  /// Where `path`, which is how the store sees it, is on this machine.
+  pub fn real_path(&self, path: &Path) -> PathBuf {
+    self.root.join(path.strip_prefix("/").unwrap_or(path))
+  }
+
+  pub fn real_store_dir(&self) -> PathBuf {
+    self.real_path(&self.store_dir)
+  }
+
+  pub fn real_state_dir(&self) -> PathBuf {
+    self.real_path(&self.state_dir)
   }
 
-  pub fn root(&self) -> &Path {
-    &self.0
+  pub fn real_log_dir(&self) -> PathBuf {
+    self.real_path(&self.log_dir)
   }
 
-  pub fn store_dir(&self) -> PathBuf {
-    self.root().join("store")
+  /// Where `LocalStore::optimise_store` keeps one copy of every file.
+  pub fn links_dir(&self) -> PathBuf {
+    self.real_store_dir().join(".links")
   }
 
-  pub fn state_dir(&self) -> PathBuf {
-    self.root().join("var").join("nix")
+  /// Where paths are moved to just before they are deleted.
+  pub fn trash_dir(&self) -> PathBuf {
+    self.real_store_dir().join("trash")
+  }
+
+  /// The big lock that the garbage collector holds while it runs, and that
+  /// schema upgrades hold too.
+  pub fn gc_lock(&self) -> PathBuf {
+    self.real_state_dir().join("gc.lock")
   }
 
   pub fn db_dir(&self) -> PathBuf {
-    self.state_dir().join("db")
+    self.real_state_dir().join("db")
+  }
+
+  /// A file that takes up some disk space, so that the garbage collector
+  /// can still get going when the disk is full.
+  pub fn reserved_space(&self) -> PathBuf {
+    self.db_dir().join("reserved")
+  }
+
+  /// Holds the schema version of the database, for the benefit of older
+  /// versions of Nix.
+  pub fn schema_file(&self) -> PathBuf {
+    self.db_dir().join("schema")
+  }
+
+  pub fn db_file(&self) -> PathBuf {
+    self.db_dir().join("db.sqlite")
   }
 
   pub fn temproots_dir(&self) -> PathBuf {
-    self.state_dir().join("temproots")
+    self.real_state_dir().join("temproots")
+  }
+
+  pub fn gcroots_dir(&self) -> PathBuf {
+    self.real_state_dir().join("gcroots")
   }
 }
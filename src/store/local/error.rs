@@ -2,6 +2,7 @@ use crate::{
   hash::{Encoding, Hash},
   prelude::*,
 };
+use std::time::Duration;
 
 #[derive(Debug, Error)]
 pub enum Error {
@@ -22,4 +23,27 @@ pub enum Error {
     expected: usize,
     actual: usize,
   },
+  #[error("timed out after {timeout:?} waiting for lock on `{}'", path.display())]
+  LockTimeout { path: PathBuf, timeout: Duration },
+  #[error("the store at `{}' was opened read-only", store_dir.display())]
+  ReadOnly { store_dir: PathBuf },
+  #[error(
+    "the database at `{}' has schema version {version}, but only up to {supported} is supported",
+    path.display()
+  )]
+  SchemaTooNew {
+    path: PathBuf,
+    version: i64,
+    supported: i64,
+  },
+  #[error(
+    "the database at `{}' has schema version {version}, which is too old to upgrade; upgrade it to \
+     at least version {oldest} with an older Nix first",
+    path.display()
+  )]
+  SchemaTooOld {
+    path: PathBuf,
+    version: i64,
+    oldest: i64,
+  },
 }
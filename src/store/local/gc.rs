@@ -1,14 +1,1187 @@
-use super::{dirs::Dirs, lock::*};
+use super::{dirs::Dirs, lock::*, LocalStore};
+use crate::{
+  path::{Path as StorePath, PathSet},
+  prelude::*,
+  Store,
+};
 use anyhow::Result;
-use tokio::fs::File;
+use futures::future::{BoxFuture, FutureExt, Shared};
+use std::{
+  collections::{BTreeMap, BTreeSet},
+  os::unix::{fs::MetadataExt, io::AsRawFd},
+  path::{Path, PathBuf},
+  process,
+  sync::Arc,
+  time::{Duration, Instant},
+};
+use tokio::{
+  fs::{self, File},
+  io::{AsyncReadExt, AsyncWriteExt},
+};
 
-pub async fn open_gc_lock(d: &Dirs, l: LockType) -> Result<File> {
-  let gc_lock = d.state_dir().join("gc.lock");
+pub async fn open_gc_lock(d: &Dirs, l: LockType, timeout: Option<Duration>) -> Result<File> {
+  let gc_lock = d.gc_lock();
   debug!("acquiring global GC lock at `{}'", gc_lock.display());
   let f = File::create(&gc_lock).await?;
-  if !f.try_lock(l)? {
-    info!("waiting for the big GC lock at `{}'...", gc_lock.display());
-    f.lock(l).await?;
-  }
+  lock_file(&f, &gc_lock, l, timeout).await?;
   Ok(f)
 }
+
+/// How much space to keep back in `Dirs::reserved_space`.
+pub const RESERVED_SPACE: u64 = 8 * 1024 * 1024;
+
+/// The strongly connected components of the graph with the given `edges`,
+/// each coming after every component it has an edge to. This is Tarjan's
+/// algorithm, with an explicit stack so that long chains of references
+/// don't overflow the real one.
+fn strongly_connected<T: Ord + Clone>(edges: &BTreeMap<T, BTreeSet<T>>) -> Vec<Vec<T>> {
+  struct Node {
+    index: usize,
+    low_link: usize,
+    on_stack: bool,
+  }
+
+  let mut nodes = BTreeMap::<&T, Node>::new();
+  let mut stack = vec![];
+  let mut components = vec![];
+
+  for start in edges.keys() {
+    if nodes.contains_key(start) {
+      continue;
+    }
+    let mut calls = vec![(start, edges[start].iter())];
+    let index = nodes.len();
+    nodes.insert(
+      start,
+      Node {
+        index,
+        low_link: index,
+        on_stack: true,
+      },
+    );
+    stack.push(start);
+
+    while let Some((v, successors)) = calls.last_mut() {
+      let v = *v;
+      if let Some(w) = successors.next() {
+        match nodes.get(w) {
+          None if edges.contains_key(w) => {
+            let index = nodes.len();
+            nodes.insert(
+              w,
+              Node {
+                index,
+                low_link: index,
+                on_stack: true,
+              },
+            );
+            stack.push(w);
+            calls.push((w, edges[w].iter()));
+          }
+          Some(n) if n.on_stack => {
+            let index = n.index;
+            let node = nodes.get_mut(v).unwrap();
+            node.low_link = node.low_link.min(index);
+          }
+          _ => {}
+        }
+        continue;
+      }
+
+      calls.pop();
+      let Node {
+        index, low_link, ..
+      } = nodes[v];
+      if let Some((parent, _)) = calls.last() {
+        let parent = nodes.get_mut(*parent).unwrap();
+        parent.low_link = parent.low_link.min(low_link);
+      }
+      if low_link == index {
+        let mut component = vec![];
+        loop {
+          let w = stack.pop().unwrap();
+          nodes.get_mut(w).unwrap().on_stack = false;
+          component.push(w.clone());
+          if w == v {
+            break;
+          }
+        }
+        components.push(component);
+      }
+    }
+  }
+
+  components
+}
+
+/// Make sure that `Dirs::reserved_space` takes up `size` bytes on disk.
+pub fn reserve_space(d: &Dirs, size: u64) -> Result<()> {
+  let path = d.reserved_space();
+  match std::fs::metadata(&path) {
+    Ok(m) if m.len() == size => return Ok(()),
+    Ok(_) => {}
+    Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+    Err(e) => return Err(e.into()),
+  }
+  let f = std::fs::File::create(&path)?;
+  if let Err(e) = nix::fcntl::posix_fallocate(f.as_raw_fd(), 0, size as libc::off_t) {
+    // no space is better than having garbage in it
+    let _ = std::fs::remove_file(&path);
+    return Err(e).with_context(|| format!("while reserving space in `{}'", path.display()));
+  }
+  Ok(())
+}
+
+#[derive(Default, Debug)]
+pub struct GcResults {
+  /// Everything that was deleted from the store directory, valid or not, or
+  /// for the print actions, the paths that were asked for.
+  pub paths: BTreeSet<PathBuf>,
+  pub bytes_freed: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GcAction {
+  /// Report the live paths without deleting anything.
+  PrintLive,
+  /// Report the paths that would be deleted without deleting them.
+  PrintDead,
+  /// Delete everything that isn't live.
+  DeleteDead,
+  /// Delete `GcOptions::paths_to_delete`, failing if any of them is live.
+  DeleteSpecific,
+}
+
+#[derive(Debug, Clone)]
+pub struct GcOptions {
+  pub action: GcAction,
+  pub paths_to_delete: PathSet,
+  /// Stop deleting once at least this many bytes have been freed.
+  pub max_freed: u64,
+  /// Keep the outputs of live derivations alive.
+  pub keep_outputs: bool,
+  /// Keep the derivations that produced live paths alive.
+  pub keep_derivations: bool,
+}
+
+impl Default for GcOptions {
+  fn default() -> Self {
+    Self {
+      action: GcAction::DeleteDead,
+      paths_to_delete: PathSet::new(),
+      max_freed: u64::MAX,
+      keep_outputs: false,
+      keep_derivations: false,
+    }
+  }
+}
+
+/// When to collect garbage automatically before importing paths.
+#[derive(Debug, Clone)]
+pub struct AutoGcSettings {
+  /// Collect garbage when fewer than this many bytes are free on the store's
+  /// filesystem. Zero disables automatic collection.
+  pub min_free: u64,
+  /// How many bytes should be free once automatic collection is done.
+  pub max_free: u64,
+  /// How long to go without looking at the free space again.
+  pub check_interval: Duration,
+}
+
+impl Default for AutoGcSettings {
+  fn default() -> Self {
+    Self {
+      min_free: 0,
+      max_free: u64::MAX,
+      check_interval: Duration::from_secs(5),
+    }
+  }
+}
+
+#[derive(Default)]
+pub(super) struct AutoGcState {
+  last_check: Option<Instant>,
+  /// The collection running in the background, if there is one.
+  running: Option<Shared<BoxFuture<'static, ()>>>,
+}
+
+/// Clears `AutoGcState::running` when the collection finishes or is
+/// cancelled.
+struct AutoGcGuard(Arc<std::sync::Mutex<AutoGcState>>);
+
+impl Drop for AutoGcGuard {
+  fn drop(&mut self) {
+    self.0.lock().unwrap().running = None;
+  }
+}
+
+/// Files next to a store path that belong to it while it's being worked on.
+pub(super) static TEMP_SUFFIXES: [&str; 3] = [".lock", ".check", ".old"];
+
+/// Map from each root to the links that keep it alive.
+pub type Roots = BTreeMap<StorePath, BTreeSet<PathBuf>>;
+
+impl LocalStore {
+  pub(super) fn temp_roots_path(&self) -> PathBuf {
+    self.dirs.temproots_dir().join(process::id().to_string())
+  }
+
+  pub(super) async fn create_temp_roots_file(&self) -> Result<File> {
+    let file = self.temp_roots_path();
+    loop {
+      let all_gc_roots = open_gc_lock(&self.dirs, LockType::Read, self.lock_timeout)
+        .await
+        .context("acquiring GC lock")?;
+      let _ = fs::remove_file(&file).await;
+      let temproots_file = fs::OpenOptions::new()
+        .create_new(true)
+        .write(true)
+        .open(&file)
+        .await
+        .with_context(|| format!("while opening temproots file {}", file.display()))?;
+      drop(all_gc_roots);
+      debug!("acquiring read lock on `{}'", file.display());
+      lock_file(&temproots_file, &file, LockType::Read, self.lock_timeout).await?;
+      // the garbage collector marks the files of dead processes before
+      // deleting them, in case somebody opened it in the meantime
+      if temproots_file.metadata().await?.len() == 0 {
+        return Ok(temproots_file);
+      }
+    }
+  }
+
+  /// Collect garbage according to `options`: report or delete the paths in
+  /// the store that aren't reachable from a root.
+  pub async fn collect_garbage(&self, options: &GcOptions) -> Result<GcResults> {
+    self.check_writable()?;
+    let _gc_lock = open_gc_lock(&self.dirs, LockType::Write, self.lock_timeout).await?;
+
+    let mut roots = self.find_roots().await?;
+    self.find_runtime_roots(&mut roots).await?;
+    let mut live = roots.into_keys().collect::<PathSet>();
+    // these stay read-locked until we're done, so that their owners can't add
+    // any more roots behind our back
+    let mut temp_root_fds = vec![];
+    live.extend(self.read_temp_roots(&mut temp_root_fds).await?);
+    let live = self.compute_live_paths(&live, options).await?;
+    debug!("found {} live paths", live.len());
+
+    let mut results = GcResults::default();
+    if options.action == GcAction::PrintLive {
+      results.paths = live.iter().map(|p| self.to_real_path(p)).collect();
+      return Ok(results);
+    }
+
+    let valid = self.db.get_valid_paths().await?;
+    let (dead, junk) = if options.action == GcAction::DeleteSpecific {
+      if let Some(p) = options.paths_to_delete.intersection(&live).next() {
+        bail!(
+          "cannot delete path `{}' since it is still alive",
+          self.print_store_path(p)
+        );
+      }
+      let junk = options
+        .paths_to_delete
+        .difference(&valid)
+        .map(|p| self.to_real_path(p))
+        .collect();
+      (
+        options
+          .paths_to_delete
+          .intersection(&valid)
+          .cloned()
+          .collect(),
+        junk,
+      )
+    } else {
+      (
+        valid.difference(&live).cloned().collect(),
+        self.find_junk(&valid, &live).await?,
+      )
+    };
+    let dead = self.group_referrers_first(dead).await?;
+
+    if options.action == GcAction::PrintDead {
+      results.paths = dead
+        .iter()
+        .flatten()
+        .map(|p| self.to_real_path(p))
+        .chain(junk)
+        .collect();
+      return Ok(results);
+    }
+
+    // the disk may be full, so give back the space we kept for this; it's
+    // reserved again the next time the store is opened
+    if let Err(e) = fs::remove_file(self.dirs.reserved_space()).await {
+      if e.kind() != std::io::ErrorKind::NotFound {
+        return Err(e.into());
+      }
+    }
+
+    for group in dead {
+      if results.bytes_freed >= options.max_freed {
+        break;
+      }
+      self.delete_valid_paths(&group, &mut results).await?;
+    }
+    for path in junk {
+      if results.bytes_freed >= options.max_freed {
+        break;
+      }
+      results.bytes_freed += delete_path(&path).await?;
+      results.paths.insert(path);
+    }
+    if results.bytes_freed >= options.max_freed {
+      info!("deleted more than {} bytes; stopping", options.max_freed);
+    }
+    results.bytes_freed += self.remove_unused_links().await?;
+
+    drop(temp_root_fds);
+    info!(
+      "deleted {} paths, freeing {} bytes",
+      results.paths.len(),
+      results.bytes_freed
+    );
+    Ok(results)
+  }
+
+  /// Delete `paths` from the store. Nothing is deleted if any of them is a
+  /// root or is still referred to by a valid path outside of `paths`.
+  pub async fn delete_paths(&self, paths: &PathSet) -> Result<GcResults> {
+    self.check_writable()?;
+    let _gc_lock = open_gc_lock(&self.dirs, LockType::Write, self.lock_timeout).await?;
+
+    let mut roots = self.find_roots().await?;
+    self.find_runtime_roots(&mut roots).await?;
+    let mut temp_root_fds = vec![];
+    let temp_roots = self.read_temp_roots(&mut temp_root_fds).await?;
+    for path in paths {
+      if let Some(link) = roots.get(path).and_then(|links| links.iter().next()) {
+        bail!(
+          "cannot delete path `{}' since it is a root (from `{}')",
+          self.print_store_path(path),
+          link.display()
+        );
+      }
+      if temp_roots.contains(path) {
+        bail!(
+          "cannot delete path `{}' since it is a temporary root",
+          self.print_store_path(path)
+        );
+      }
+      if !self.is_valid_path(path).await? {
+        bail!("path `{}' is not valid", self.print_store_path(path));
+      }
+    }
+
+    let mut results = GcResults::default();
+    for group in self.group_referrers_first(paths.clone()).await? {
+      self.delete_valid_paths(&group, &mut results).await?;
+    }
+    drop(temp_root_fds);
+    Ok(results)
+  }
+
+  /// Unregister and delete `paths`, which may refer to each other but to
+  /// nothing else that's being deleted. Directories are first moved into the
+  /// trash directory, so that nobody sees them half-deleted.
+  async fn delete_valid_paths(&self, paths: &[StorePath], results: &mut GcResults) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mut moved = vec![];
+    for path in paths {
+      let real_path = self.to_real_path(path);
+      let trashed = self.dirs.trash_dir().join(path.to_string());
+      let meta = match fs::symlink_metadata(&real_path).await {
+        Ok(meta) if meta.is_dir() => meta,
+        _ => continue,
+      };
+      fs::create_dir_all(self.dirs.trash_dir()).await?;
+      // moving a directory to another parent updates its `..' entry
+      if meta.permissions().mode() & 0o200 == 0 {
+        fs::set_permissions(&real_path, std::fs::Permissions::from_mode(0o755)).await?;
+      }
+      fs::rename(&real_path, &trashed).await.with_context(|| {
+        format!(
+          "while moving `{}' to `{}'",
+          real_path.display(),
+          trashed.display()
+        )
+      })?;
+      moved.push((real_path, trashed));
+    }
+
+    // any remaining referrer from outside of `paths` makes this fail
+    if let Err(e) = self.db.invalidate_paths(paths.to_vec()).await {
+      for (real_path, trashed) in &moved {
+        fs::rename(trashed, real_path).await?;
+      }
+      return Err(e);
+    }
+
+    for path in paths {
+      let real_path = self.to_real_path(path);
+      let trashed = moved.iter().find(|(p, _)| *p == real_path).map(|(_, t)| t);
+      results.bytes_freed += delete_path(trashed.unwrap_or(&real_path)).await?;
+      results.paths.insert(real_path);
+    }
+    Ok(())
+  }
+
+  /// Collect garbage in the background if the store's filesystem is low on
+  /// space, until `max_free` bytes are free. This only waits for the
+  /// collection while fewer than `min_free` bytes are free, and failures are
+  /// logged rather than returned.
+  pub(super) async fn auto_gc(&self) {
+    let settings = &self.auto_gc_settings;
+    if settings.min_free == 0 {
+      return;
+    }
+    let real_store_dir = self.dirs.real_store_dir();
+
+    let collection = {
+      let mut state = self.auto_gc_state.lock().unwrap();
+      match &state.running {
+        Some(collection) => collection.clone(),
+        None => {
+          if state
+            .last_check
+            .is_some_and(|t| t.elapsed() < settings.check_interval)
+          {
+            return;
+          }
+          state.last_check = Some(Instant::now());
+          let avail = match free_space(&real_store_dir) {
+            Ok(avail) => avail,
+            Err(e) => {
+              warn!(
+                "cannot get the free space in `{}': {:#}",
+                real_store_dir.display(),
+                e
+              );
+              return;
+            }
+          };
+          if avail >= settings.min_free {
+            return;
+          }
+
+          info!(
+            "only {} bytes free in `{}'; collecting garbage",
+            avail,
+            real_store_dir.display()
+          );
+          let store = self.background_handle();
+          let options = GcOptions {
+            max_freed: settings.max_free.saturating_sub(avail),
+            ..Default::default()
+          };
+          let guard = AutoGcGuard(self.auto_gc_state.clone());
+          let collection = tokio::spawn(async move {
+            let _guard = guard;
+            if let Err(e) = store.collect_garbage(&options).await {
+              warn!("while collecting garbage automatically: {:#}", e);
+            }
+          })
+          .map(|_| ())
+          .boxed()
+          .shared();
+          state.running = Some(collection.clone());
+          collection
+        }
+      }
+    };
+
+    match free_space(&real_store_dir) {
+      Ok(avail) if avail >= settings.min_free => {}
+      _ => {
+        debug!("waiting for the automatic garbage collection to finish");
+        collection.await
+      }
+    }
+  }
+
+  /// Compute the closure of `roots`, plus the outputs of live derivations
+  /// and the derivers of live paths if `options` asks to keep them. Roots
+  /// that aren't valid yet, like paths that are being imported, are live
+  /// but have no closure.
+  async fn compute_live_paths(&self, roots: &PathSet, options: &GcOptions) -> Result<PathSet> {
+    let mut live = PathSet::new();
+    let mut queue = roots.iter().cloned().collect::<Vec<_>>();
+    while let Some(path) = queue.pop() {
+      if live.contains(&path) {
+        continue;
+      }
+      if let Some(info) = self.get_path_info(&path).await? {
+        queue.extend(info.references().iter().cloned());
+        if options.keep_derivations {
+          if let Some(deriver) = info.deriver() {
+            if self.is_valid_path(deriver).await? {
+              queue.push(deriver.clone());
+            }
+          }
+        }
+        if options.keep_outputs && path.is_derivation() {
+          for out in self.db.get_derivation_outputs(&path).await? {
+            if self.is_valid_path(&out).await? {
+              queue.push(out);
+            }
+          }
+        }
+      }
+      live.insert(path);
+    }
+    Ok(live)
+  }
+
+  /// Split `paths` into groups of paths that refer to each other, ordered
+  /// so that each group comes before anything it refers to. Fails if a path
+  /// outside of `paths` still refers to one of them.
+  async fn group_referrers_first(&self, paths: PathSet) -> Result<Vec<Vec<StorePath>>> {
+    let mut referrers = BTreeMap::new();
+    for path in &paths {
+      let mut r = self.get_referrers(path).await?;
+      r.remove(path);
+      if let Some(other) = r.difference(&paths).next() {
+        bail!(
+          "cannot delete path `{}' because it is still referenced by `{}'",
+          self.print_store_path(path),
+          self.print_store_path(other)
+        );
+      }
+      referrers.insert(path.clone(), r);
+    }
+    Ok(strongly_connected(&referrers))
+  }
+
+  /// Find everything in the store directory that isn't a valid path, except
+  /// for temporary files belonging to live paths.
+  async fn find_junk(&self, valid: &PathSet, live: &PathSet) -> Result<Vec<PathBuf>> {
+    let mut junk = vec![];
+    let mut entries = fs::read_dir(self.dirs.real_store_dir()).await?;
+    while let Some(entry) = entries.next_entry().await? {
+      let name = entry.file_name();
+      let name = match name.to_str() {
+        Some(n) => n,
+        None => continue,
+      };
+      // unused links are cleaned up separately
+      if name == ".links" {
+        continue;
+      }
+      if let Ok(p) = StorePath::from_base_name(name) {
+        if valid.contains(&p) {
+          continue;
+        }
+      }
+      let base = TEMP_SUFFIXES
+        .iter()
+        .find_map(|suffix| name.strip_suffix(suffix))
+        .or_else(|| super::parse_temp_sibling(name).map(|(base, _)| base))
+        .unwrap_or(name);
+      if let Ok(p) = StorePath::from_base_name(base) {
+        if live.contains(&p) {
+          continue;
+        }
+      }
+      junk.push(entry.path());
+    }
+    Ok(junk)
+  }
+
+  /// Find the roots in the `gcroots` directory. A symlink there is a root if
+  /// it points into the store, or if it points at another symlink which
+  /// does.
+  pub async fn find_roots(&self) -> Result<Roots> {
+    let mut roots = Roots::new();
+    self
+      .find_roots_in(&self.dirs.gcroots_dir(), &mut roots)
+      .await?;
+    Ok(roots)
+  }
+
+  #[async_recursion]
+  async fn find_roots_in(&self, path: &Path, roots: &mut Roots) -> Result<()> {
+    let meta = match fs::symlink_metadata(path).await {
+      Ok(m) => m,
+      Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+      Err(e) => return Err(e.into()),
+    };
+
+    if meta.is_dir() {
+      let mut entries = fs::read_dir(path).await?;
+      while let Some(entry) = entries.next_entry().await? {
+        self.find_roots_in(&entry.path(), roots).await?;
+      }
+    } else if meta.file_type().is_symlink() {
+      let target = path
+        .parent()
+        .unwrap_or(path)
+        .join(fs::read_link(path).await?);
+      if target.starts_with(self.store_path()) {
+        self.found_root(path, &target, roots).await?;
+      } else if fs::symlink_metadata(&target).await.is_err() {
+        if path.starts_with(self.dirs.gcroots_dir().join("auto")) {
+          info!(
+            "removing stale link from `{}' to `{}'",
+            path.display(),
+            target.display()
+          );
+          fs::remove_file(path).await?;
+        }
+      } else if fs::symlink_metadata(&target)
+        .await?
+        .file_type()
+        .is_symlink()
+      {
+        let target2 = target
+          .parent()
+          .unwrap_or(&target)
+          .join(fs::read_link(&target).await?);
+        if target2.starts_with(self.store_path()) {
+          self.found_root(&target, &target2, roots).await?;
+        }
+      }
+    } else if meta.is_file() {
+      // a regular file named after a store path is a root for that path
+      if let Some(Ok(p)) = path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .map(StorePath::from_base_name)
+      {
+        if self.is_valid_path(&p).await? {
+          roots.entry(p).or_default().insert(path.into());
+        }
+      }
+    }
+
+    Ok(())
+  }
+
+  async fn found_root(&self, link: &Path, target: &Path, roots: &mut Roots) -> Result<()> {
+    let p = self.parse_store_path(
+      &target
+        .components()
+        .take(self.store_path().components().count() + 1)
+        .collect::<PathBuf>(),
+    )?;
+    if self.is_valid_path(&p).await? {
+      roots.entry(p).or_default().insert(link.into());
+    } else {
+      info!(
+        "skipping invalid root from `{}' to `{}'",
+        link.display(),
+        target.display()
+      );
+    }
+    Ok(())
+  }
+
+  /// Find the paths used by running processes: their executables, working
+  /// directories and open files, and any store paths mentioned in their
+  /// memory mappings or environments.
+  pub async fn find_runtime_roots(&self, roots: &mut Roots) -> Result<()> {
+    let mut procs = match fs::read_dir("/proc").await {
+      Ok(p) => p,
+      Err(e) => {
+        debug!("not looking for runtime roots: cannot read /proc: {}", e);
+        return Ok(());
+      }
+    };
+    // processes can exit at any point while we look at them, which shows up
+    // as errors from reading their directories
+    loop {
+      let entry = match procs.next_entry().await {
+        Ok(Some(entry)) => entry,
+        Ok(None) => break,
+        Err(e) => {
+          debug!("skipping an entry of /proc: {}", e);
+          continue;
+        }
+      };
+      let is_pid = entry
+        .file_name()
+        .to_str()
+        .is_some_and(|n| n.bytes().all(|b| b.is_ascii_digit()));
+      if !is_pid {
+        continue;
+      }
+      let proc = entry.path();
+
+      let mut links = vec![proc.join("exe"), proc.join("cwd")];
+      match fs::read_dir(proc.join("fd")).await {
+        Ok(mut fds) => loop {
+          match fds.next_entry().await {
+            Ok(Some(fd)) => links.push(fd.path()),
+            Ok(None) => break,
+            Err(e) => {
+              debug!("skipping open files of `{}': {}", proc.display(), e);
+              continue;
+            }
+          }
+        },
+        Err(e) => debug!("skipping open files of `{}': {}", proc.display(), e),
+      }
+      for link in links {
+        match fs::read_link(&link).await {
+          Ok(target) => self.found_runtime_root(&link, &target, roots).await?,
+          Err(e) => debug!("skipping `{}': {}", link.display(), e),
+        }
+      }
+
+      for file in &["maps", "environ"] {
+        let file = proc.join(file);
+        match fs::read(&file).await {
+          Ok(contents) => {
+            for path in find_store_paths(&self.store_path(), &contents) {
+              self.found_runtime_root(&file, &path, roots).await?;
+            }
+          }
+          Err(e) => debug!("skipping `{}': {}", file.display(), e),
+        }
+      }
+    }
+    Ok(())
+  }
+
+  async fn found_runtime_root(&self, link: &Path, target: &Path, roots: &mut Roots) -> Result<()> {
+    // most of what processes have open is outside the store, and anything
+    // that has since been deleted doesn't need protecting
+    if let Ok(p) = self.store_path_of(target) {
+      if self.is_valid_path(&p).await? {
+        roots.entry(p).or_default().insert(link.into());
+      }
+    }
+    Ok(())
+  }
+
+  /// Read the temporary roots of every live process, leaving each file
+  /// read-locked in `fds`. Files belonging to dead processes are removed.
+  async fn read_temp_roots(&self, fds: &mut Vec<File>) -> Result<PathSet> {
+    let mut roots = PathSet::new();
+    let mut entries = fs::read_dir(self.dirs.temproots_dir()).await?;
+    while let Some(entry) = entries.next_entry().await? {
+      let path = entry.path();
+      let mut file = match fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&path)
+        .await
+      {
+        Ok(f) => f,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+        Err(e) => return Err(e.into()),
+      };
+
+      if file.try_lock(LockType::Write)? {
+        info!("removing stale temporary roots file `{}'", path.display());
+        fs::remove_file(&path).await?;
+        file.write_all(b"d").await?;
+        continue;
+      }
+
+      debug!("reading temporary roots from `{}'", path.display());
+      lock_file(&file, &path, LockType::Read, self.lock_timeout).await?;
+      let mut contents = String::new();
+      file.read_to_string(&mut contents).await?;
+      for root in contents.split('\0').filter(|r| !r.is_empty()) {
+        roots.insert(self.parse_store_path(Path::new(root))?);
+      }
+      fds.push(file);
+    }
+    Ok(roots)
+  }
+}
+
+/// The number of bytes available to unprivileged users on the filesystem
+/// containing `path`.
+fn free_space(path: &Path) -> Result<u64> {
+  let st = nix::sys::statvfs::statvfs(path)?;
+  Ok(st.blocks_available() as u64 * st.fragment_size() as u64)
+}
+
+/// Find everything in `contents` that looks like a path in `store_dir`. The
+/// results aren't necessarily valid store paths.
+fn find_store_paths(store_dir: &Path, contents: &[u8]) -> Vec<PathBuf> {
+  let prefix = format!("{}/", store_dir.display());
+  let prefix = prefix.as_bytes();
+  let is_path_char = |c: &u8| c.is_ascii_alphanumeric() || b"+-._?=".contains(c);
+
+  let mut paths = vec![];
+  let mut i = 0;
+  while i + prefix.len() <= contents.len() {
+    if !contents[i..].starts_with(prefix) {
+      i += 1;
+      continue;
+    }
+    let start = i + prefix.len();
+    let len = contents[start..]
+      .iter()
+      .take_while(|c| is_path_char(c))
+      .count();
+    if let Ok(name) = std::str::from_utf8(&contents[start..start + len]) {
+      paths.push(store_dir.join(name));
+    }
+    i = start + len;
+  }
+  paths
+}
+
+/// Recursively delete `path`, making directories writable as needed.
+/// Returns the number of bytes freed, not counting files that have other hard
+/// links.
+#[async_recursion]
+pub async fn delete_path(path: &Path) -> Result<u64> {
+  use std::os::unix::fs::PermissionsExt;
+
+  let meta = match fs::symlink_metadata(path).await {
+    Ok(m) => m,
+    Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+    Err(e) => return Err(e.into()),
+  };
+  let mut freed = if meta.nlink() == 1 || meta.is_dir() {
+    meta.blocks() * 512
+  } else {
+    0
+  };
+
+  if meta.is_dir() {
+    if meta.permissions().mode() & 0o200 == 0 {
+      fs::set_permissions(path, std::fs::Permissions::from_mode(0o755)).await?;
+    }
+    let mut entries = fs::read_dir(path).await?;
+    while let Some(entry) = entries.next_entry().await? {
+      freed += delete_path(&entry.path()).await?;
+    }
+    fs::remove_dir(path).await?;
+  } else {
+    fs::remove_file(path).await?;
+  }
+
+  Ok(freed)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    path_info::ValidPathInfo,
+    util::{test_derivation, test_path_info},
+  };
+  use std::os::unix::fs::symlink;
+
+  /// Create and register a path without making it a temporary root.
+  async fn add_path(store: &LocalStore, name: &str, refs: &[&StorePath]) -> Result<StorePath> {
+    let path = store.store_path_for_text(name, name, refs.iter().copied())?;
+    register(store, &path, name, refs, None).await?;
+    Ok(path)
+  }
+
+  async fn register(
+    store: &LocalStore,
+    path: &StorePath,
+    contents: &str,
+    refs: &[&StorePath],
+    deriver: Option<&StorePath>,
+  ) -> Result<()> {
+    fs::write(store.print_store_path(path), contents).await?;
+    store
+      .register_valid_paths(&[ValidPathInfo {
+        deriver: deriver.cloned(),
+        ..test_path_info(path, contents.as_bytes(), refs)
+      }])
+      .await?;
+    Ok(())
+  }
+
+  #[test]
+  fn collect() -> Result<()> {
+    crate::util::run_test(async {
+      let temp = tempfile::tempdir()?;
+      let store = LocalStore::open(temp.path())?;
+      let dep = add_path(&store, "dep", &[]).await?;
+      let rooted = add_path(&store, "rooted", &[&dep]).await?;
+      let indirect = add_path(&store, "indirect", &[]).await?;
+      let temp_root = add_path(&store, "temp-root", &[]).await?;
+      let dead = add_path(&store, "dead", &[]).await?;
+      let dead_referrer = add_path(&store, "dead-referrer", &[&dead]).await?;
+      fs::write(store.store_path().join("junk"), "junk").await?;
+
+      symlink(
+        store.print_store_path(&rooted),
+        store.dirs.gcroots_dir().join("rooted"),
+      )?;
+      let result = temp.path().join("result");
+      symlink(store.print_store_path(&indirect), &result)?;
+      fs::create_dir(store.dirs.gcroots_dir().join("auto")).await?;
+      symlink(&result, store.dirs.gcroots_dir().join("auto").join("a"))?;
+      symlink(
+        temp.path().join("nonexistent"),
+        store.dirs.gcroots_dir().join("auto").join("b"),
+      )?;
+      store.add_temp_root(&temp_root).await?;
+
+      let roots = store.find_roots().await?;
+      assert_eq!(roots.len(), 2);
+      assert!(roots.contains_key(&indirect));
+      assert!(
+        fs::symlink_metadata(store.dirs.gcroots_dir().join("auto").join("b"))
+          .await
+          .is_err()
+      );
+
+      let results = store.collect_garbage(&GcOptions::default()).await?;
+      assert_eq!(results.paths.len(), 3);
+      assert!(results.paths.contains(&store.store_path().join("junk")));
+      assert!(results.bytes_freed > 0);
+      for p in &[&dep, &rooted, &indirect, &temp_root] {
+        assert!(store.is_valid_path(p).await?);
+      }
+      for p in &[&dead, &dead_referrer] {
+        assert!(!store.is_valid_path(p).await?);
+        assert!(fs::symlink_metadata(store.print_store_path(p))
+          .await
+          .is_err());
+      }
+
+      Ok(())
+    })
+  }
+
+  #[test]
+  fn collect_cycles() -> Result<()> {
+    crate::util::run_test(async {
+      let temp = tempfile::tempdir()?;
+      let store = LocalStore::open(temp.path())?;
+      // like the `out' and `dev' outputs of one derivation
+      let out = store.store_path_for_text("out", "out", None.into_iter())?;
+      let dev = store.store_path_for_text("dev", "dev", None.into_iter())?;
+      fs::write(store.print_store_path(&out), "out").await?;
+      fs::create_dir(store.print_store_path(&dev)).await?;
+      store
+        .register_valid_paths(&[
+          test_path_info(&out, b"out", &[&dev]),
+          test_path_info(&dev, b"dev", &[&out]),
+        ])
+        .await?;
+      let user = add_path(&store, "user", &[&out]).await?;
+
+      let err = store
+        .delete_paths(&vec![out.clone(), dev.clone()].into_iter().collect())
+        .await
+        .unwrap_err();
+      assert!(err.to_string().contains("still referenced"), "{}", err);
+
+      let results = store.collect_garbage(&GcOptions::default()).await?;
+      assert_eq!(results.paths.len(), 3);
+      for p in &[&out, &dev, &user] {
+        assert!(!store.is_valid_path(p).await?);
+        assert!(fs::symlink_metadata(store.print_store_path(p))
+          .await
+          .is_err());
+      }
+
+      Ok(())
+    })
+  }
+
+  #[test]
+  fn options() -> Result<()> {
+    crate::util::run_test(async {
+      use crate::derivation::Derivation;
+
+      let temp = tempfile::tempdir()?;
+      let store = LocalStore::open(temp.path())?;
+      let src = add_path(&store, "src", &[]).await?;
+      let out = store.store_path_for_text("out", "out", None.into_iter())?;
+      let drv = Derivation {
+        input_srcs: Some(src.clone()).into_iter().collect(),
+        ..test_derivation(&out, &[])
+      }
+      .unparse(&store);
+      let drv_path = store.store_path_for_text("out.drv", &drv, Some(&src).into_iter())?;
+      register(&store, &drv_path, &drv, &[&src], None).await?;
+      register(&store, &out, "out", &[], Some(&drv_path)).await?;
+      let dead = add_path(&store, "dead", &[]).await?;
+      let root = store.dirs.gcroots_dir().join("root");
+      let real = |p: &StorePath| store.store_path().join(p.to_string());
+
+      // a live output keeps its derivation alive only if asked to
+      symlink(store.print_store_path(&out), &root)?;
+      let print_live = GcOptions {
+        action: GcAction::PrintLive,
+        ..Default::default()
+      };
+      let results = store.collect_garbage(&print_live).await?;
+      assert_eq!(results.paths, Some(real(&out)).into_iter().collect());
+      let results = store
+        .collect_garbage(&GcOptions {
+          keep_derivations: true,
+          ..print_live.clone()
+        })
+        .await?;
+      assert_eq!(results.paths.len(), 3);
+
+      // and a live derivation keeps its outputs alive only if asked to
+      fs::remove_file(&root).await?;
+      symlink(store.print_store_path(&drv_path), &root)?;
+      let print_dead = GcOptions {
+        action: GcAction::PrintDead,
+        ..Default::default()
+      };
+      let results = store.collect_garbage(&print_dead).await?;
+      assert_eq!(
+        results.paths,
+        vec![real(&out), real(&dead)].into_iter().collect()
+      );
+      let results = store
+        .collect_garbage(&GcOptions {
+          keep_outputs: true,
+          ..print_dead
+        })
+        .await?;
+      assert_eq!(results.paths, Some(real(&dead)).into_iter().collect());
+      assert!(store.is_valid_path(&dead).await?);
+
+      let delete_specific = |p: &StorePath| GcOptions {
+        action: GcAction::DeleteSpecific,
+        paths_to_delete: Some(p.clone()).into_iter().collect(),
+        ..Default::default()
+      };
+      assert!(store.collect_garbage(&delete_specific(&src)).await.is_err());
+      let results = store.collect_garbage(&delete_specific(&out)).await?;
+      assert_eq!(results.paths, Some(real(&out)).into_iter().collect());
+      assert!(store.is_valid_path(&dead).await?);
+
+      add_path(&store, "dead-too", &[]).await?;
+      let results = store
+        .collect_garbage(&GcOptions {
+          max_freed: 1,
+          ..Default::default()
+        })
+        .await?;
+      assert_eq!(results.paths.len(), 1);
+
+      Ok(())
+    })
+  }
+
+  #[test]
+  fn delete_paths() -> Result<()> {
+    crate::util::run_test(async {
+      let temp = tempfile::tempdir()?;
+      let store = LocalStore::open(temp.path())?;
+      let dep = add_path(&store, "dep", &[]).await?;
+      let referrer = add_path(&store, "referrer", &[&dep]).await?;
+      let rooted = add_path(&store, "rooted", &[]).await?;
+      symlink(
+        store.print_store_path(&rooted),
+        store.dirs.gcroots_dir().join("rooted"),
+      )?;
+
+      // a directory that refers to itself
+      let dir = store.store_path_for_text("dir", "dir", None.into_iter())?;
+      register(&store, &dir, "", &[&dir], None).await?;
+      fs::remove_file(store.print_store_path(&dir)).await?;
+      fs::create_dir(store.print_store_path(&dir)).await?;
+      fs::write(store.store_path().join(dir.to_string()).join("file"), "x").await?;
+      fs::set_permissions(
+        store.print_store_path(&dir),
+        std::os::unix::fs::PermissionsExt::from_mode(0o555),
+      )
+      .await?;
+
+      let set = |paths: &[&StorePath]| paths.iter().map(|p| (*p).clone()).collect::<PathSet>();
+      let err = store.delete_paths(&set(&[&dep])).await.unwrap_err();
+      assert!(err.to_string().contains("still referenced"), "{}", err);
+      let err = store.delete_paths(&set(&[&rooted])).await.unwrap_err();
+      assert!(err.to_string().contains("is a root"), "{}", err);
+      assert!(store.is_valid_path(&dep).await?);
+
+      let results = store.delete_paths(&set(&[&dep, &referrer, &dir])).await?;
+      assert_eq!(results.paths.len(), 3);
+      for p in &[&dep, &referrer, &dir] {
+        assert!(!store.is_valid_path(p).await?);
+        assert!(fs::symlink_metadata(store.print_store_path(p))
+          .await
+          .is_err());
+      }
+      assert!(fs::read_dir(store.dirs.trash_dir())
+        .await?
+        .next_entry()
+        .await?
+        .is_none());
+      assert!(store.is_valid_path(&rooted).await?);
+
+      Ok(())
+    })
+  }
+
+  #[test]
+  fn auto_gc() -> Result<()> {
+    crate::util::run_test(async {
+      use crate::archive::{dump_path, ArchiveSink, PathFilter};
+
+      let temp = tempfile::tempdir()?;
+      let mut store = LocalStore::open(temp.path())?;
+      store.set_auto_gc_settings(AutoGcSettings {
+        min_free: u64::MAX,
+        ..Default::default()
+      });
+      let dead = add_path(&store, "dead", &[]).await?;
+
+      let src = temp.path().join("src");
+      fs::write(&src, "imported").await?;
+      let mut nar = ArchiveSink::new(vec![]);
+      dump_path(&src, &mut nar, &PathFilter::always()).await?;
+      let nar = nar.into_inner().concat();
+      let imported = store.store_path_for_text("imported", "imported", None.into_iter())?;
+      let info = test_path_info(&imported, &nar, &[]);
+      store
+        .add_nar_to_store(
+          &info,
+          futures::stream::iter(Some(Ok(Bytes::from(nar)))),
+          false,
+        )
+        .await?;
+
+      assert!(store.is_valid_path(&imported).await?);
+      assert!(!store.is_valid_path(&dead).await?);
+      assert!(store.auto_gc_state.lock().unwrap().running.is_none());
+
+      Ok(())
+    })
+  }
+
+  #[test]
+  fn runtime_roots() -> Result<()> {
+    crate::util::run_test(async {
+      let temp = tempfile::tempdir()?;
+      let store = LocalStore::open(temp.path())?;
+      let open = add_path(&store, "open", &[]).await?;
+      let closed = add_path(&store, "closed", &[]).await?;
+      let _file = std::fs::File::open(store.print_store_path(&open))?;
+
+      let mut roots = Roots::new();
+      store.find_runtime_roots(&mut roots).await?;
+      assert!(roots.contains_key(&open));
+      assert!(!roots.contains_key(&closed));
+
+      let environ = format!(
+        "PATH={}/bin:/usr/bin\0LD_PRELOAD={}/lib/libfoo.so\0",
+        store.print_store_path(&open),
+        store.print_store_path(&closed)
+      );
+      assert_eq!(
+        find_store_paths(&store.store_path(), environ.as_bytes()),
+        vec![
+          PathBuf::from(store.print_store_path(&open)),
+          PathBuf::from(store.print_store_path(&closed))
+        ]
+      );
+
+      Ok(())
+    })
+  }
+}
@@ -1,17 +1,25 @@
+use super::error::Error;
 use crate::prelude::*;
 use anyhow::Result;
-use futures::Future;
 use nix::{
   errno::EWOULDBLOCK,
   fcntl::{self, FlockArg},
+  unistd,
 };
 use std::{
+  collections::BTreeSet,
   os::unix::io::AsRawFd,
-  path::PathBuf,
-  pin::Pin,
-  task::{Context, Poll},
+  path::{Path, PathBuf},
+  time::Duration,
 };
-use tokio::fs::{self, File};
+use tokio::{
+  fs::{self, File},
+  time::{delay_for, Instant},
+};
+
+/// The longest we sleep between attempts at taking a lock someone else
+/// holds.
+const MAX_LOCK_DELAY: Duration = Duration::from_millis(500);
 
 #[derive(Copy, Clone, PartialEq, Eq)]
 pub enum LockType {
@@ -33,7 +41,6 @@ impl LockType {
 
 pub trait FsExt2 {
   fn try_lock(&self, ty: LockType) -> Result<bool>;
-  fn lock(&self, ty: LockType) -> FileLock;
 }
 
 impl FsExt2 for fs::File {
@@ -46,84 +53,472 @@ impl FsExt2 for fs::File {
     }
     Ok(true)
   }
-
-  fn lock(&self, ty: LockType) -> FileLock {
-    FileLock { file: self, ty }
-  }
 }
 
-pub struct FileLock<'a> {
-  file: &'a File,
+/// Acquire a lock of type `ty` on `file`, which is the lock file `path`,
+/// waiting for as long as it takes or until `timeout` has passed. We poll
+/// rather than block in `flock`, so that giving up, whether on a timeout or
+/// because this is cancelled, leaves nothing behind that still waits.
+pub async fn lock_file(
+  file: &File,
+  path: &Path,
   ty: LockType,
-}
-
-impl<'a> Future for FileLock<'a> {
-  type Output = Result<()>;
+  timeout: Option<Duration>,
+) -> Result<()> {
+  if file.try_lock(ty)? {
+    return Ok(());
+  }
+  info!("waiting for lock on `{}'...", path.display());
 
-  fn poll(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Self::Output> {
-    if let Err(e) = fcntl::flock(self.file.as_raw_fd(), self.ty.flag()) {
-      if e.as_errno() == Some(EWOULDBLOCK) {
-        return Poll::Pending;
+  let deadline = timeout.map(|t| (Instant::now() + t, t));
+  let mut backoff = Duration::from_millis(1);
+  loop {
+    let mut delay = backoff;
+    if let Some((deadline, timeout)) = deadline {
+      let now = Instant::now();
+      if now >= deadline {
+        bail!(Error::LockTimeout {
+          path: path.into(),
+          timeout,
+        });
       }
-      return Poll::Ready(Err(e.into()));
+      delay = delay.min(deadline - now);
+    }
+    delay_for(delay).await;
+    if file
+      .try_lock(ty)
+      .with_context(|| format!("while locking `{}'", path.display()))?
+    {
+      break;
     }
-    Poll::Ready(Ok(()))
+    backoff = (backoff * 2).min(MAX_LOCK_DELAY);
   }
+  debug!("lock acquired on `{}'", path.display());
+  Ok(())
+}
+
+/// The lock file that guards `path`.
+fn lock_path(path: &Path) -> PathBuf {
+  let mut p = path.as_os_str().to_os_string();
+  p.push(".lock");
+  p.into()
 }
 
+/// Locks on a set of paths, each held through a `<path>.lock` file.
+///
+/// Lock files can be deleted once the operation they guard has succeeded:
+/// the holder first writes a byte into the file to mark it stale and then
+/// unlinks it. Anyone who was waiting on the old file finds the mark once
+/// they get the lock and starts over with a fresh file.
 #[derive(Default, Debug)]
-pub struct PathLocks(Vec<File>);
+pub struct PathLocks {
+  files: Vec<(File, PathBuf)>,
+  /// How long to wait for each lock before giving up.
+  timeout: Option<Duration>,
+  /// Whether to delete the lock files on release.
+  deletion: bool,
+}
 
 impl PathLocks {
   pub fn new() -> Self {
     Self::default()
   }
 
+  pub fn with_timeout(timeout: Duration) -> Self {
+    let mut locks = Self::new();
+    locks.timeout = Some(timeout);
+    locks
+  }
+
+  /// Lock all of `paths`. They are locked in sorted order, so that two
+  /// processes locking overlapping sets can't deadlock. Without `wait`,
+  /// nothing stays locked if any of them is held by someone else, and this
+  /// returns false.
   pub async fn lock<I: IntoIterator<Item = PathBuf>>(
     &mut self,
     paths: I,
     wait: bool,
     message: Option<&'static str>,
   ) -> Result<bool> {
-    assert!(self.0.is_empty());
-    for path in paths {
-      let lock_path = match path.extension() {
-        Some(e) => path.with_extension({
-          let mut e = e.to_os_string();
-          e.push(".lock");
-          e
-        }),
-        None => path.with_extension(".lock"),
-      };
-      loop {
-        let lockfile = fs::File::create(&lock_path).await?;
-        if !lockfile.try_lock(LockType::Write)? {
-          if wait {
-            if let Some(m) = message {
-              error!("{}", m);
-            }
-            lockfile.lock(LockType::Write).await?;
-          } else {
-            self.unlock();
-            return Ok(false);
-          }
+    assert!(self.files.is_empty());
+    let paths = paths.into_iter().collect::<BTreeSet<_>>();
+    for path in &paths {
+      if !self.lock_one(path, wait, message).await? {
+        self.unlock();
+        return Ok(false);
+      }
+    }
+    Ok(true)
+  }
+
+  /// Lock whichever of `paths` nobody else holds right now, and return the
+  /// ones that were held.
+  pub async fn lock_available<I: IntoIterator<Item = PathBuf>>(
+    &mut self,
+    paths: I,
+  ) -> Result<BTreeSet<PathBuf>> {
+    assert!(self.files.is_empty());
+    let mut unlocked = BTreeSet::new();
+    for path in paths.into_iter().collect::<BTreeSet<_>>() {
+      if !self.lock_one(&path, false, None).await? {
+        unlocked.insert(path);
+      }
+    }
+    Ok(unlocked)
+  }
+
+  async fn lock_one(&mut self, path: &Path, wait: bool, message: Option<&str>) -> Result<bool> {
+    let lock_path = lock_path(path);
+    loop {
+      // don't truncate, that would hide the stale mark
+      let lockfile = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&lock_path)
+        .await
+        .with_context(|| format!("while opening lock file `{}'", lock_path.display()))?;
+      if !lockfile.try_lock(LockType::Write)? {
+        if !wait {
+          return Ok(false);
         }
-        debug!("lock acquired on `{}'", lock_path.display());
-        let meta = fs::metadata(&lock_path).await?;
-        if meta.len() != 0 {
-          debug!("lock file `{}' has become stale", lock_path.display());
-        } else {
-          self.0.push(lockfile);
-          break;
+        if let Some(m) = message {
+          error!("{}", m);
         }
+        lock_file(&lockfile, &lock_path, LockType::Write, self.timeout).await?;
+      }
+      debug!("lock acquired on `{}'", lock_path.display());
+      // check the file we hold rather than whatever is at the path now
+      if lockfile.metadata().await?.len() != 0 {
+        debug!("lock file `{}' has become stale", lock_path.display());
+      } else {
+        self.files.push((lockfile, lock_path));
+        return Ok(true);
       }
     }
-    Ok(true)
+  }
+
+  /// Delete the lock files when releasing them. Only do this once the
+  /// guarded operation has succeeded.
+  pub fn set_deletion(&mut self, deletion: bool) {
+    self.deletion = deletion;
   }
 
   pub fn unlock(&mut self) {
-    for it in self.0.drain(..) {
-      let _ = it.try_lock(LockType::Unlock);
+    for (file, path) in self.files.drain(..) {
+      if self.deletion {
+        delete_lock_file(&file, &path);
+      }
+      let _ = file.try_lock(LockType::Unlock);
+    }
+  }
+}
+
+impl Drop for PathLocks {
+  fn drop(&mut self) {
+    self.unlock();
+  }
+}
+
+/// Mark the lock file `file` at `path`, which we hold, as stale and remove
+/// it.
+fn delete_lock_file(file: &File, path: &Path) {
+  // the mark has to be there before anyone can get the lock on it
+  if let Err(e) = unistd::write(file.as_raw_fd(), b"d") {
+    warn!("cannot mark lock file `{}' as stale: {}", path.display(), e);
+    return;
+  }
+  if let Err(e) = std::fs::remove_file(path) {
+    warn!("cannot delete lock file `{}': {}", path.display(), e);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn wait_for_lock() -> Result<()> {
+    crate::util::run_test(async {
+      let temp = tempfile::tempdir()?;
+      let path = temp.path().join("lock");
+      let holder = File::create(&path).await?;
+      let waiter = File::create(&path).await?;
+      lock_file(&holder, &path, LockType::Write, None).await?;
+
+      let err = lock_file(
+        &waiter,
+        &path,
+        LockType::Write,
+        Some(Duration::from_millis(50)),
+      )
+      .await
+      .unwrap_err();
+      assert_matches::assert_matches!(err.downcast_ref::<Error>(), Some(Error::LockTimeout { .. }));
+
+      // the waiter that timed out mustn't end up holding the lock
+      holder.try_lock(LockType::Unlock)?;
+      delay_for(Duration::from_millis(50)).await;
+      assert!(holder.try_lock(LockType::Write)?);
+
+      let (acquired, ()) = futures::join!(lock_file(&waiter, &path, LockType::Read, None), async {
+        delay_for(Duration::from_millis(50)).await;
+        holder.try_lock(LockType::Unlock).unwrap();
+      });
+      acquired?;
+      assert!(!holder.try_lock(LockType::Write)?);
+
+      Ok(())
+    })
+  }
+
+  /// Giving up on a lock mustn't leave a thread behind that keeps waiting
+  /// for it, or the runtime would wait for that thread when it shuts down.
+  #[test]
+  fn timeout_leaves_no_waiter() -> Result<()> {
+    let temp = tempfile::tempdir()?;
+    let path = temp.path().join("lock");
+    let holder = std::fs::File::create(&path)?;
+    fcntl::flock(holder.as_raw_fd(), FlockArg::LockExclusiveNonblock)?;
+    // let go eventually, so that a waiter left behind can't hang the test
+    let releaser = std::thread::spawn(move || {
+      std::thread::sleep(Duration::from_secs(3));
+      drop(holder);
+    });
+
+    let mut runtime = tokio::runtime::Runtime::new()?;
+    let err = runtime
+      .block_on(async {
+        let waiter = File::create(&path).await?;
+        lock_file(
+          &waiter,
+          &path,
+          LockType::Write,
+          Some(Duration::from_millis(50)),
+        )
+        .await
+      })
+      .unwrap_err();
+    assert_matches::assert_matches!(err.downcast_ref::<Error>(), Some(Error::LockTimeout { .. }));
+    let start = std::time::Instant::now();
+    drop(runtime);
+    assert!(start.elapsed() < Duration::from_secs(1));
+
+    releaser.join().unwrap();
+    Ok(())
+  }
+
+  #[test]
+  fn path_locks_timeout() -> Result<()> {
+    crate::util::run_test(async {
+      let temp = tempfile::tempdir()?;
+      let path = temp.path().join("foo");
+      let mut first = PathLocks::new();
+      assert!(first.lock(vec![path.clone()], true, None).await?);
+
+      let mut second = PathLocks::with_timeout(Duration::from_millis(50));
+      let err = second
+        .lock(vec![path.clone()], true, None)
+        .await
+        .unwrap_err();
+      assert_matches::assert_matches!(err.downcast_ref::<Error>(), Some(Error::LockTimeout { .. }));
+
+      first.unlock();
+      let mut second = PathLocks::with_timeout(Duration::from_millis(50));
+      assert!(second.lock(vec![path], true, None).await?);
+
+      Ok(())
+    })
+  }
+
+  /// Run the test `name` of this module in a separate process, with `env`
+  /// telling it what to do.
+  fn spawn_child(name: &str, env: &[(&str, &Path)]) -> Result<std::process::Child> {
+    let mut cmd = std::process::Command::new(std::env::current_exe()?);
+    cmd
+      .args(["--exact", &format!("store::local::lock::tests::{}", name)])
+      .args(["--test-threads", "1", "--quiet"])
+      .stdout(std::process::Stdio::null());
+    for (k, v) in env {
+      cmd.env(k, v);
     }
+    Ok(cmd.spawn()?)
+  }
+
+  /// The other side of `stale_lock_files` and `contended_lock_files`: lock
+  /// `LOCK_TEST_PATH`, log to `LOCK_TEST_LOG` while holding it, and delete
+  /// the lock file on release. Does nothing when run as an ordinary test.
+  #[test]
+  fn lock_child() -> Result<()> {
+    let path = match std::env::var_os("LOCK_TEST_PATH") {
+      Some(p) => PathBuf::from(p),
+      None => return Ok(()),
+    };
+    let log = PathBuf::from(std::env::var_os("LOCK_TEST_LOG").unwrap());
+    let ready = std::env::var_os("LOCK_TEST_READY").map(PathBuf::from);
+    crate::util::run_test(async move {
+      let mut locks = PathLocks::new();
+      assert!(locks.lock(vec![path], true, None).await?);
+      let append = |line: &'static str| -> Result<()> {
+        use std::io::Write;
+        let mut f = std::fs::OpenOptions::new()
+          .append(true)
+          .create(true)
+          .truncate(false)
+          .open(&log)?;
+        f.write_all(line.as_bytes())?;
+        Ok(())
+      };
+      append("start\n")?;
+      if let Some(ready) = ready {
+        fs::write(ready, "").await?;
+      }
+      delay_for(Duration::from_millis(100)).await;
+      append("end\n")?;
+      locks.set_deletion(true);
+      locks.unlock();
+      Ok(())
+    })
+  }
+
+  #[test]
+  fn stale_lock_files() -> Result<()> {
+    crate::util::run_test(async {
+      let temp = tempfile::tempdir()?;
+      let path = temp.path().join("foo");
+      let lock = temp.path().join("foo.lock");
+      let log = temp.path().join("log");
+      let ready = temp.path().join("ready");
+      let mut child = spawn_child(
+        "lock_child",
+        &[
+          ("LOCK_TEST_PATH", &path),
+          ("LOCK_TEST_LOG", &log),
+          ("LOCK_TEST_READY", &ready),
+        ],
+      )?;
+      while fs::metadata(&ready).await.is_err() {
+        delay_for(Duration::from_millis(10)).await;
+      }
+      let old = std::fs::File::open(&lock)?;
+
+      // we end up waiting on the file the child deletes, and have to move
+      // on to a fresh one
+      let mut locks = PathLocks::new();
+      assert!(locks.lock(vec![path.clone()], true, None).await?);
+      assert!(child.wait()?.success());
+      assert_eq!(fs::read_to_string(&log).await?, "start\nend\n");
+      assert_eq!(old.metadata()?.len(), 1);
+      assert_eq!(fs::metadata(&lock).await?.len(), 0);
+
+      // without deletion, the lock file stays as it is
+      locks.unlock();
+      assert_eq!(fs::metadata(&lock).await?.len(), 0);
+      assert!(locks.lock(vec![path], true, None).await?);
+      locks.set_deletion(true);
+      locks.unlock();
+      assert!(fs::metadata(&lock).await.is_err());
+
+      Ok(())
+    })
+  }
+
+  #[test]
+  fn contended_lock_files() -> Result<()> {
+    crate::util::run_test(async {
+      let temp = tempfile::tempdir()?;
+      let path = temp.path().join("foo");
+      let log = temp.path().join("log");
+      let children = (0..4)
+        .map(|_| {
+          spawn_child(
+            "lock_child",
+            &[("LOCK_TEST_PATH", &path), ("LOCK_TEST_LOG", &log)],
+          )
+        })
+        .collect::<Result<Vec<_>>>()?;
+      for mut child in children {
+        assert!(child.wait()?.success());
+      }
+
+      // nobody ever got in while someone else held the lock
+      assert_eq!(fs::read_to_string(&log).await?, "start\nend\n".repeat(4));
+      assert!(fs::metadata(temp.path().join("foo.lock")).await.is_err());
+
+      Ok(())
+    })
+  }
+
+  #[test]
+  fn lock_order() -> Result<()> {
+    crate::util::run_test(async {
+      let temp = tempfile::tempdir()?;
+      let paths = ["a", "b", "c"]
+        .iter()
+        .map(|n| temp.path().join(n))
+        .collect::<Vec<_>>();
+
+      // a path that's given twice mustn't wait for itself
+      let mut locks = PathLocks::with_timeout(Duration::from_millis(50));
+      let twice = vec![paths[1].clone(), paths[0].clone(), paths[1].clone()];
+      assert!(locks.lock(twice, true, None).await?);
+      drop(locks);
+
+      // opposite orders would deadlock if they were taken as given
+      let timeout = Duration::from_secs(5);
+      let (mut first, mut second) = (
+        PathLocks::with_timeout(timeout),
+        PathLocks::with_timeout(timeout),
+      );
+      let reversed = paths.iter().rev().cloned().collect::<Vec<_>>();
+      for _ in 0..20 {
+        let (a, b) = futures::join!(
+          async {
+            let res = first.lock(paths.clone(), true, None).await;
+            delay_for(Duration::from_millis(1)).await;
+            first.unlock();
+            res
+          },
+          async {
+            let res = second.lock(reversed.clone(), true, None).await;
+            delay_for(Duration::from_millis(1)).await;
+            second.unlock();
+            res
+          }
+        );
+        assert!(a? && b?);
+      }
+
+      Ok(())
+    })
+  }
+
+  #[test]
+  fn lock_available() -> Result<()> {
+    crate::util::run_test(async {
+      let temp = tempfile::tempdir()?;
+      let paths = ["a", "b", "c"]
+        .iter()
+        .map(|n| temp.path().join(n))
+        .collect::<Vec<_>>();
+      let mut held = PathLocks::new();
+      assert!(held.lock(Some(paths[1].clone()), true, None).await?);
+
+      let mut locks = PathLocks::new();
+      let unlocked = locks.lock_available(paths.clone()).await?;
+      assert_eq!(unlocked, Some(paths[1].clone()).into_iter().collect());
+
+      // without waiting, it's all or nothing
+      let mut all = PathLocks::new();
+      assert!(!all.lock(paths.clone(), false, None).await?);
+      drop(held);
+      assert!(!all.lock(paths.clone(), false, None).await?);
+
+      // dropping releases them
+      drop(locks);
+      assert!(all.lock(paths, false, None).await?);
+
+      Ok(())
+    })
   }
 }
@@ -1,7 +1,7 @@
-use self::dirs::Dirs;
 use super::ByteStream;
 use crate::{
   archive::{ArchiveSink, PathFilter},
+  derivation::Derivation,
   hash::{self, Hash, HashType},
   path::Path as StorePath,
   path_info::{PathInfo, ValidPathInfo},
@@ -12,19 +12,22 @@ use crypto::digest::Digest;
 use db::Db;
 use error::Error;
 use futures::{lock::Mutex, TryStreamExt};
-use lock::{FsExt2, LockType, PathLocks};
+use lock::{lock_file, LockType};
 use nix::{
   sys::{stat::*, time::TimeSpec},
   unistd::{fchownat, getegid, geteuid, getuid, FchownatFlags},
 };
 use std::{
   borrow::Cow,
-  collections::{BTreeSet, HashSet},
+  collections::{BTreeMap, BTreeSet, HashSet},
   iter,
   path::{Path, PathBuf},
   process,
-  sync::Arc,
-  time::SystemTime,
+  sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+  },
+  time::{Duration, SystemTime},
 };
 use tokio::{fs, io::AsyncWriteExt};
 
@@ -33,25 +36,48 @@ mod dirs;
 mod error;
 mod gc;
 mod lock;
+mod optimise;
+mod verify;
+
+pub use db::{DbConfig, Synchronous};
+pub use dirs::Dirs;
+pub use gc::{AutoGcSettings, GcAction, GcOptions, GcResults, RESERVED_SPACE};
+pub use lock::PathLocks;
+pub use optimise::OptimiseStats;
+pub use verify::{Problem, VerifyResults};
 
 pub struct LocalStore {
   dirs: Dirs,
-  db: Mutex<Db>,
+  db: Db,
+  /// This process's temporary roots file, created on first use.
+  temp_roots: Mutex<Option<fs::File>>,
+  auto_gc_settings: AutoGcSettings,
+  auto_gc_state: Arc<std::sync::Mutex<gc::AutoGcState>>,
+  /// Whether to deduplicate the files of new paths as they are added.
+  auto_optimise: bool,
+  /// How long to wait for a lock held by someone else before failing with
+  /// `Error::LockTimeout`. Without one, we wait for as long as it takes.
+  lock_timeout: Option<Duration>,
+  read_only: bool,
 }
 
 #[async_trait]
 impl Store for LocalStore {
   fn store_path(&self) -> Cow<Path> {
-    Cow::Owned(self.dirs.store_dir())
+    Cow::Borrowed(&self.dirs.store_dir)
   }
 
   fn get_uri(&self) -> String {
     String::from("local")
   }
 
+  fn to_real_path(&self, p: &StorePath) -> PathBuf {
+    self.dirs.real_store_dir().join(p.to_string())
+  }
+
   async fn get_path_info(&self, path: &StorePath) -> Result<Option<Arc<dyn PathInfo>>> {
     // i think i have to destructure here because map() requires Sized
-    if let Some(x) = self.db.lock().await.get_path_info(self, path)? {
+    if let Some(x) = self.db.get_path_info(path).await? {
       Ok(Some(Arc::new(x)))
     } else {
       Ok(None)
@@ -59,35 +85,36 @@ impl Store for LocalStore {
   }
 
   async fn get_referrers(&self, path: &StorePath) -> Result<BTreeSet<StorePath>> {
-    self.db.lock().await.get_referrers(self, path)
+    self.db.get_referrers(path).await
   }
 
   async fn add_temp_root(&self, path: &StorePath) -> Result<()> {
-    let file = self.dirs.temproots_dir().join(process::id().to_string());
-    let mut temp_file = loop {
-      let all_gc_roots = gc::open_gc_lock(&self.dirs, LockType::Read)
-        .await
-        .context("acquiring GC lock")?;
-      let _ = fs::remove_file(&file).await;
-      let temproots_file = fs::OpenOptions::new()
-        .create_new(true)
-        .write(true)
-        .open(&file)
-        .await
-        .with_context(|| format!("while opening temproots file {}", file.display()))?;
-      drop(all_gc_roots);
-      debug!("acquiring read lock on `{}'", file.display());
-      temproots_file.lock(LockType::Read).await?;
-      if temproots_file.metadata().await?.len() == 0 {
-        break temproots_file;
-      }
-    };
-    debug!("acquiring write lock on `{}'", file.display());
-    temp_file.lock(LockType::Write).await?;
-    temp_file
-      .write(self.print_store_path(path).as_bytes())
-      .await?;
-    temp_file.lock(LockType::Read).await?;
+    self.check_writable()?;
+    let mut temp_roots = self.temp_roots.lock().await;
+    if temp_roots.is_none() {
+      *temp_roots = Some(self.create_temp_roots_file().await?);
+    }
+    let temp_file = temp_roots.as_mut().unwrap();
+
+    let temp_roots_path = self.temp_roots_path();
+    debug!("acquiring write lock on temproots file");
+    lock_file(
+      temp_file,
+      &temp_roots_path,
+      LockType::Write,
+      self.lock_timeout,
+    )
+    .await?;
+    let mut root = self.print_store_path(path);
+    root.push('\0');
+    temp_file.write_all(root.as_bytes()).await?;
+    lock_file(
+      temp_file,
+      &temp_roots_path,
+      LockType::Read,
+      self.lock_timeout,
+    )
+    .await?;
     Ok(())
   }
 
@@ -95,7 +122,9 @@ impl Store for LocalStore {
     &self,
     info: &ValidPathInfo,
     source: S,
+    repair: bool,
   ) -> Result<()> {
+    self.check_writable()?;
     self
       .add_temp_root(&info.store_path)
       .await
@@ -106,53 +135,41 @@ impl Store for LocalStore {
         )
       })?;
 
-    if !self.is_valid_path(&info.store_path).await? {
-      let mut locks = PathLocks::new();
-      let real_path = self
-        .store_path()
-        .join(PathBuf::from(info.store_path.to_string()));
-
-      locks.lock(Some(real_path.clone()), false, None).await?;
-
-      if !self.is_valid_path(&info.store_path).await? {
-        let _ = fs::remove_file(&real_path).await;
+    if repair || !self.is_valid_path(&info.store_path).await? {
+      let mut locks = self.path_locks();
+      let real_path = self.to_real_path(&info.store_path);
 
-        let mut hash_sink = hash::Context::new(HashType::SHA256);
+      // someone else importing the same path has to finish first; if they
+      // succeed, the path is valid by the time we check again
+      locks.lock(Some(real_path.clone()), true, None).await?;
 
-        crate::archive::restore_into(
-          &real_path,
-          source.and_then(|bytes| {
-            hash_sink.input(&bytes);
-            futures::future::ok(bytes)
-          }),
-        )
-        .await?;
+      let valid = self.is_valid_path(&info.store_path).await?;
+      if repair || !valid {
+        // nothing appears under the real name until it has been checked
+        let temp = temp_sibling(&real_path);
 
-        let (hash, hash_len) = hash_sink.finish();
-
-        if hash != info.nar_hash {
-          bail!(Error::NarHashMismatch {
-            path: self.print_store_path(&info.store_path).into(),
-            expected: info.nar_hash.clone(),
-            actual: hash
-          });
-        }
-        if hash_len != info.nar_size.unwrap_or_default() as usize {
-          bail!(Error::NarSizeMismatch {
-            path: self.print_store_path(&info.store_path).into(),
-            expected: info.nar_size.unwrap_or_default() as usize,
-            actual: hash_len
-          });
+        // our temporary root keeps the collector away from the path we're
+        // importing
+        self.auto_gc().await;
+        if let Err(e) = self.restore_nar(&temp, info, source).await {
+          let _ = gc::delete_path(&temp).await;
+          return Err(e);
         }
 
-        // self.auto_gc().await?;
+        replace_valid_path(&real_path, &temp).await?;
 
-        self.canonicalise_path_metadata(&real_path, None).await?;
-
-        // self.optimise(&real_path).await?;
+        if self.auto_optimise {
+          self.optimise_path(&real_path).await?;
+        }
 
-        self.db.lock().await.insert_valid_paths(self, Some(info))?;
+        if !valid {
+          self
+            .register_valid_paths(std::slice::from_ref(info))
+            .await?;
+        }
       }
+      // the path is valid now, so nobody needs the lock file any more
+      locks.set_deletion(true);
     }
     Ok(())
   }
@@ -163,68 +180,300 @@ impl Store for LocalStore {
     path: &Path,
     algo: HashType,
     filter: PathFilter,
-    _repair: bool,
+    repair: bool,
   ) -> Result<StorePath> {
+    self.check_writable()?;
     let fpath = fs::canonicalize(path).await?;
-    let meta = fs::metadata(&fpath).await?;
-    let (contents_hash, _) = Hash::hash_file(&fpath, algo).await?;
+    let recursive = fs::metadata(&fpath).await?.is_dir();
+    let contents_hash = if recursive {
+      let mut h = ArchiveSink::new(crate::hash::Sink::new(algo));
+      crate::archive::dump_path(&fpath, &mut h, &filter).await?;
+      h.into_inner().finish().0
+    } else {
+      Hash::hash_file(&fpath, algo).await?.0
+    };
     let dest =
-      self.make_fixed_output_path(meta.is_dir(), &contents_hash, name, iter::empty(), false)?;
+      self.make_fixed_output_path(recursive, &contents_hash, name, iter::empty(), false)?;
     self.add_temp_root(&dest).await?;
-    if !self.is_valid_path(&dest).await? {
-      let real_path = self.store_path().join(PathBuf::from(dest.to_string()));
-      let _ = fs::remove_file(&real_path).await;
-      if meta.is_dir() {
-        unimplemented!("recursive add not yet supported");
-      } else {
-        fs::copy(&fpath, &real_path).await.with_context(|| {
+    if repair || !self.is_valid_path(&dest).await? {
+      let real_path = self.to_real_path(&dest);
+      let mut locks = self.path_locks();
+      locks.lock(Some(real_path.clone()), true, None).await?;
+
+      let existing = self.db.get_path_info(&dest).await?;
+      let valid = existing.is_some();
+      if repair || !valid {
+        // nothing appears under the real name until it has been checked
+        let temp = temp_sibling(&real_path);
+        let copied = async {
+          copy_tree(&fpath, &temp, &filter).await.with_context(|| {
+            format!(
+              "while copying contents from {} to {}",
+              fpath.display(),
+              temp.display()
+            )
+          })?;
+
+          self.canonicalise_path_metadata(&temp, None).await?;
+
+          let mut h = ArchiveSink::new(crate::hash::Sink::new(HashType::SHA256));
+          crate::archive::dump_path(&temp, &mut h, &PathFilter::always()).await?;
+          let (hash, size) = h.into_inner().finish();
+
+          if let Some(info) = &existing {
+            if hash != info.nar_hash {
+              bail!(Error::NarHashMismatch {
+                path: real_path.clone(),
+                expected: info.nar_hash.clone(),
+                actual: hash,
+              });
+            }
+          }
+          Ok((hash, size))
+        }
+        .await;
+        let (hash, size) = match copied {
+          Ok(x) => x,
+          Err(e) => {
+            let _ = gc::delete_path(&temp).await;
+            return Err(e);
+          }
+        };
+
+        replace_valid_path(&real_path, &temp).await?;
+
+        if self.auto_optimise {
+          self.optimise_path(&real_path).await?;
+        }
+
+        if !valid {
+          let vpi = ValidPathInfo {
+            store_path: dest.clone(),
+            deriver: None,
+            nar_hash: hash,
+            references: Default::default(),
+            registration_time: SystemTime::now(),
+            nar_size: Some(size as u64),
+            id: 0,
+            signatures: Default::default(),
+            content_addressed: Default::default(),
+            ultimate: true,
+          };
+
+          self.register_valid_paths(&[vpi]).await?;
+        }
+      }
+      locks.set_deletion(true);
+    }
+    Ok(dest)
+  }
+}
+
+impl LocalStore {
+  /// Register the freshly built `outputs` of `drv`, enforcing its reference
+  /// restrictions first.
+  pub async fn register_outputs(&self, drv: &Derivation, outputs: &[ValidPathInfo]) -> Result<()> {
+    self.check_writable()?;
+    crate::build::check_outputs(self, drv, outputs).await?;
+    self.register_valid_paths(outputs).await
+  }
+
+  /// Register `infos` as valid, along with the outputs of the derivations
+  /// among them.
+  async fn register_valid_paths(&self, infos: &[ValidPathInfo]) -> Result<()> {
+    let mut drvs = BTreeMap::new();
+    for info in infos.iter().filter(|i| i.store_path.is_derivation()) {
+      let drv = self
+        .read_derivation(&info.store_path)
+        .await
+        .with_context(|| {
           format!(
-            "while copying contents from {} to {}",
-            fpath.display(),
-            real_path.display()
+            "while registering the outputs of `{}'",
+            self.print_store_path(&info.store_path)
           )
         })?;
+      drvs.insert(info.store_path.clone(), drv);
+    }
+    self.db.insert_valid_paths(infos.to_vec(), drvs).await
+  }
+
+  pub fn set_auto_gc_settings(&mut self, settings: AutoGcSettings) {
+    self.auto_gc_settings = settings;
+  }
+
+  /// Unpack the NAR `source` into `real_path`, checking that it matches
+  /// `info`, and canonicalise the result.
+  async fn restore_nar<S: ByteStream + Send + Unpin>(
+    &self,
+    real_path: &Path,
+    info: &ValidPathInfo,
+    source: S,
+  ) -> Result<()> {
+    let mut hash_sink = hash::Context::new(HashType::SHA256);
+
+    crate::archive::restore_into(
+      real_path,
+      source.and_then(|bytes| {
+        hash_sink.input(&bytes);
+        futures::future::ok(bytes)
+      }),
+    )
+    .await?;
+
+    let (hash, hash_len) = hash_sink.finish();
+
+    if hash != info.nar_hash {
+      bail!(Error::NarHashMismatch {
+        path: self.print_store_path(&info.store_path).into(),
+        expected: info.nar_hash.clone(),
+        actual: hash
+      });
+    }
+    // databases made by Nix don't always know the size
+    if let Some(expected) = info.nar_size.filter(|&s| s != 0) {
+      if hash_len != expected as usize {
+        bail!(Error::NarSizeMismatch {
+          path: self.print_store_path(&info.store_path).into(),
+          expected: expected as usize,
+          actual: hash_len
+        });
       }
+    }
+
+    self.canonicalise_path_metadata(real_path, None).await
+  }
 
-      self.canonicalise_path_metadata(&real_path, None).await?;
+  pub fn set_auto_optimise(&mut self, auto_optimise: bool) {
+    self.auto_optimise = auto_optimise;
+  }
 
-      let mut h = ArchiveSink::new(crate::hash::Sink::new(algo));
-      crate::archive::dump_path(&real_path, &mut h, &filter).await?;
-      let (hash, size) = h.into_inner().finish();
-
-      // self.optimise_path(&real_path).await?;
-
-      let vpi = ValidPathInfo {
-        store_path: dest.clone(),
-        deriver: None,
-        nar_hash: hash,
-        references: Default::default(),
-        registration_time: SystemTime::now(),
-        nar_size: Some(size as u64),
-        id: 0,
-        signatures: Default::default(),
-        content_addressed: Default::default(),
-        ultimate: true,
-      };
+  pub fn set_lock_timeout(&mut self, timeout: Option<Duration>) {
+    self.lock_timeout = timeout;
+  }
 
-      self.db.lock().await.insert_valid_paths(self, Some(&vpi))?;
+  fn path_locks(&self) -> PathLocks {
+    match self.lock_timeout {
+      Some(t) => PathLocks::with_timeout(t),
+      None => PathLocks::new(),
     }
-    Ok(dest)
   }
-}
 
-impl LocalStore {
+  /// Open the store that `Dirs::new` puts in `root`, creating it if need
+  /// be.
   pub fn open(root: &Path) -> Result<Self> {
-    let dirs = Dirs::new(root)?;
-    let this = Self {
-      db: Mutex::new(Db::open(&dirs.db_dir().join("db.sqlite"), true)?),
-      dirs,
-    };
+    Self::open_with(Dirs::new(root), &DbConfig::default())
+  }
+
+  pub fn open_with(dirs: Dirs, db_config: &DbConfig) -> Result<Self> {
+    Self::create(dirs, db_config, gc::RESERVED_SPACE)
+  }
+
+  /// Open the store that `settings` describe, creating it if need be, and
+  /// collect garbage and optimise it as they say.
+  pub fn open_with_settings(settings: &crate::settings::Settings) -> Result<Self> {
+    let mut this = Self::create(
+      settings.dirs(),
+      &settings.db_config(),
+      settings.gc_reserved_space,
+    )?;
+    this.auto_gc_settings = settings.auto_gc_settings();
+    this.auto_optimise = settings.auto_optimise_store;
+    Ok(this)
+  }
+
+  fn create(dirs: Dirs, db_config: &DbConfig, reserved_space: u64) -> Result<Self> {
+    dirs.create()?;
+    let db = Db::open(&dirs.db_file(), &dirs.store_dir, db_config, false)?;
+    db.upgrade_schema(&dirs.schema_file(), &dirs.gc_lock())?;
+    let this = Self::with_db(dirs, db, false);
     #[cfg(target_os = "linux")]
     this.make_store_writable()?;
+    this.remove_stale_temp_siblings()?;
+    gc::reserve_space(&this.dirs, reserved_space)?;
     Ok(this)
   }
 
+  /// Open an existing store, such as one that Nix made, without changing
+  /// anything about it: nothing is created, the schema isn't upgraded and
+  /// the store stays mounted as it is.
+  ///
+  /// Everything that would change the store fails with `Error::ReadOnly`.
+  pub fn open_read_only(root: &Path) -> Result<Self> {
+    Self::open_read_only_with(Dirs::new(root), &DbConfig::default())
+  }
+
+  pub fn open_read_only_with(dirs: Dirs, db_config: &DbConfig) -> Result<Self> {
+    let db = Db::open(&dirs.db_file(), &dirs.store_dir, db_config, true)?;
+    db.require_schema(&dirs.schema_file())?;
+    Ok(Self::with_db(dirs, db, true))
+  }
+
+  fn with_db(dirs: Dirs, db: Db, read_only: bool) -> Self {
+    Self {
+      db,
+      dirs,
+      temp_roots: Mutex::new(None),
+      auto_gc_settings: Default::default(),
+      auto_gc_state: Default::default(),
+      auto_optimise: false,
+      lock_timeout: None,
+      read_only,
+    }
+  }
+
+  /// Another handle on the same store, for collecting garbage in the
+  /// background. It doesn't collect garbage automatically itself.
+  fn background_handle(&self) -> Self {
+    Self {
+      auto_gc_state: self.auto_gc_state.clone(),
+      auto_optimise: self.auto_optimise,
+      lock_timeout: self.lock_timeout,
+      ..Self::with_db(self.dirs.clone(), self.db.clone(), self.read_only)
+    }
+  }
+
+  fn check_writable(&self) -> Result<()> {
+    if self.read_only {
+      bail!(Error::ReadOnly {
+        store_dir: self.dirs.real_store_dir()
+      });
+    }
+    Ok(())
+  }
+
+  /// Delete the temporary copies of paths left behind by processes that
+  /// died while importing them.
+  fn remove_stale_temp_siblings(&self) -> Result<()> {
+    use nix::{errno::Errno, sys::signal::kill, unistd::Pid};
+
+    for entry in std::fs::read_dir(self.dirs.real_store_dir())? {
+      let entry = entry?;
+      let name = entry.file_name();
+      let name = match name.to_str() {
+        Some(name) => name,
+        None => continue,
+      };
+      let pid = match parse_temp_sibling(name) {
+        Some((_, pid)) => pid,
+        None => continue,
+      };
+      match kill(Pid::from_raw(pid), None) {
+        Err(e) if e.as_errno() == Some(Errno::ESRCH) => {}
+        _ => continue,
+      }
+      // a valid path can have a name that only looks like a temporary one
+      if let Ok(p) = StorePath::from_base_name(name) {
+        if self.db.is_valid_path_blocking(&p)? {
+          continue;
+        }
+      }
+      info!("removing stale temporary path `{}'", entry.path().display());
+      remove_temp_sibling(&entry.path())
+        .with_context(|| format!("while removing `{}'", entry.path().display()))?;
+    }
+    Ok(())
+  }
+
   #[cfg(target_os = "linux")]
   fn make_store_writable(&self) -> Result<()> {
     use nix::{mount::*, sched::*, sys::statvfs::*};
@@ -233,13 +482,13 @@ impl LocalStore {
       return Ok(());
     }
 
-    let st = statvfs(&self.dirs.store_dir())?;
+    let st = statvfs(&self.dirs.real_store_dir())?;
     if st.flags().contains(FsFlags::ST_RDONLY) {
       unshare(CloneFlags::CLONE_NEWNS)?;
 
       mount::<Path, Path, str, Path>(
         None,
-        &self.dirs.store_dir(),
+        &self.dirs.real_store_dir(),
         Some("none"),
         MsFlags::MS_REMOUNT | MsFlags::MS_BIND,
         None,
@@ -247,7 +496,7 @@ impl LocalStore {
       .with_context(|| {
         format!(
           "while trying to remount `{}' as writable",
-          self.dirs.store_dir().display()
+          self.dirs.real_store_dir().display()
         )
       })?;
     }
@@ -431,6 +680,109 @@ impl LocalStore {
 fn s_isreg(mode: mode_t) -> bool {
   (mode & SFlag::S_IFMT.bits()) == SFlag::S_IFREG.bits()
 }
+/// Copy the file, symlink or directory tree `src` to `dest`, leaving out the
+/// directory entries rejected by `filter`.
+#[async_recursion]
+async fn copy_tree(src: &Path, dest: &Path, filter: &PathFilter) -> Result<()> {
+  let meta = fs::symlink_metadata(src).await?;
+  if meta.is_dir() {
+    fs::create_dir(dest).await?;
+    let mut entries = fs::read_dir(src).await?;
+    while let Some(entry) = entries.next_entry().await? {
+      if filter(&entry.path()) {
+        copy_tree(&entry.path(), &dest.join(entry.file_name()), filter).await?;
+      }
+    }
+  } else if meta.file_type().is_symlink() {
+    fs::os::unix::symlink(fs::read_link(src).await?, dest).await?;
+  } else if meta.is_file() {
+    // this carries over the permissions, and so the executable bit
+    fs::copy(src, dest).await?;
+  } else {
+    bail!("file `{}' has an unsupported type", src.display());
+  }
+  Ok(())
+}
+
+static TEMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// A unique name for a new copy of `real_path` to be put together under
+/// before it replaces the old one.
+fn temp_sibling(real_path: &Path) -> PathBuf {
+  PathBuf::from(format!(
+    "{}{}{}-{}",
+    real_path.display(),
+    TEMP_INFIX,
+    process::id(),
+    TEMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+  ))
+}
+
+/// Separates a store path's name from the rest of the name of one of its
+/// temporary copies.
+const TEMP_INFIX: &str = ".tmp-";
+
+/// If `name` is that of a temporary copy of a path, return the path's name
+/// and the pid of the process that made the copy.
+fn parse_temp_sibling(name: &str) -> Option<(&str, i32)> {
+  let (base, rest) = name.rsplit_once(TEMP_INFIX)?;
+  let (pid, counter) = rest.split_once('-')?;
+  counter.parse::<u64>().ok()?;
+  Some((base, pid.parse().ok()?))
+}
+
+/// Delete a temporary copy of a path, making its directories writable as
+/// needed.
+fn remove_temp_sibling(path: &Path) -> std::io::Result<()> {
+  use std::os::unix::fs::PermissionsExt;
+
+  let meta = std::fs::symlink_metadata(path)?;
+  if meta.is_dir() {
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o755))?;
+    for entry in std::fs::read_dir(path)? {
+      remove_temp_sibling(&entry?.path())?;
+    }
+    std::fs::remove_dir(path)
+  } else {
+    std::fs::remove_file(path)
+  }
+}
+
+/// Atomically replace the contents of the valid path `real_path` with
+/// `temp`.
+async fn replace_valid_path(real_path: &Path, temp: &Path) -> Result<()> {
+  use std::os::unix::fs::PermissionsExt;
+
+  let old = PathBuf::from(format!("{}.old", real_path.display()));
+  gc::delete_path(&old).await?;
+  let moved = match fs::symlink_metadata(real_path).await {
+    Ok(meta) => {
+      // moving a directory updates its `..' entry
+      if meta.is_dir() && meta.permissions().mode() & 0o200 == 0 {
+        fs::set_permissions(real_path, std::fs::Permissions::from_mode(0o755)).await?;
+      }
+      fs::rename(real_path, &old).await?;
+      true
+    }
+    Err(e) if e.kind() == std::io::ErrorKind::NotFound => false,
+    Err(e) => return Err(e.into()),
+  };
+  if let Err(e) = fs::rename(temp, real_path).await {
+    if moved {
+      fs::rename(&old, real_path).await?;
+    }
+    return Err(e).with_context(|| {
+      format!(
+        "while moving `{}' to `{}'",
+        temp.display(),
+        real_path.display()
+      )
+    });
+  }
+  gc::delete_path(&old).await?;
+  Ok(())
+}
+
 fn s_isdir(mode: mode_t) -> bool {
   (mode & SFlag::S_IFMT.bits()) == SFlag::S_IFDIR.bits()
 }
@@ -476,6 +828,186 @@ mod tests {
     })
   }
 
+  #[test]
+  fn add_directory() -> anyhow::Result<()> {
+    crate::util::run_test(async {
+      use std::os::unix::fs::PermissionsExt;
+
+      let store = get_local_store()?;
+      let temp = tempfile::tempdir()?;
+      let src = temp.path().join("src");
+      fs::create_dir_all(src.join("bin")).await?;
+      fs::write(src.join("bin").join("hello"), "#!/bin/sh\necho hello\n").await?;
+      fs::set_permissions(
+        src.join("bin").join("hello"),
+        std::fs::Permissions::from_mode(0o755),
+      )
+      .await?;
+      fs::os::unix::symlink("bin/hello", src.join("hello")).await?;
+      fs::write(src.join("ignored"), "").await?;
+      let filter = || PathFilter::new(|p: &Path| !p.ends_with("ignored"));
+
+      let path = store
+        .add_path_to_store("src", &src, HashType::SHA256, filter(), false)
+        .await?;
+      let (expected, _) = store
+        .store_path_for_dir("src", &src, HashType::SHA256, filter())
+        .await?;
+      assert_eq!(path, expected);
+
+      let real_path = store.store_path().join(path.to_string());
+      let hello = fs::metadata(real_path.join("bin").join("hello")).await?;
+      assert_ne!(hello.permissions().mode() & 0o100, 0);
+      assert_eq!(
+        fs::read_link(real_path.join("hello")).await?,
+        Path::new("bin/hello")
+      );
+      assert!(fs::symlink_metadata(real_path.join("ignored"))
+        .await
+        .is_err());
+
+      let info = store.get_path_info(&path).await?.unwrap();
+      let mut h = ArchiveSink::new(crate::hash::Sink::new(HashType::SHA256));
+      crate::archive::dump_path(&real_path, &mut h, &PathFilter::always()).await?;
+      assert_eq!(*info.nar_hash(), h.into_inner().finish().0);
+
+      Ok(())
+    })
+  }
+
+  #[test]
+  fn failed_import() -> anyhow::Result<()> {
+    crate::util::run_test(async {
+      let temp = tempfile::tempdir()?;
+      let store = LocalStore::open(temp.path())?;
+      let path = StorePath::from_base_name("x0xf8v9fxf3jk8zln1cwlsrmhqvp0f88-dir")?;
+      let real_path = store.store_path().join(path.to_string());
+
+      let mut nar = ArchiveSink::new(vec![]);
+      crate::archive::dump_path(
+        Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/src/archive")),
+        &mut nar,
+        &PathFilter::always(),
+      )
+      .await?;
+      let info = ValidPathInfo {
+        nar_size: None,
+        ..crate::util::test_path_info(&path, b"", &[])
+      };
+      let nar = futures::stream::iter(nar.into_inner().into_iter().map(Ok));
+      let err = store.add_nar_to_store(&info, nar, false).await.unwrap_err();
+      assert_matches::assert_matches!(
+        err.downcast_ref::<Error>(),
+        Some(Error::NarHashMismatch { .. })
+      );
+      assert!(!store.is_valid_path(&path).await?);
+      assert!(fs::symlink_metadata(&real_path).await.is_err());
+
+      // only what the importer left behind is there
+      let mut entries = fs::read_dir(store.store_path()).await?;
+      while let Some(entry) = entries.next_entry().await? {
+        assert!(entry.file_name().to_string_lossy().ends_with(".lock"));
+      }
+
+      // leftovers of dead processes go away when the store is opened
+      let stale = temp_sibling(&real_path)
+        .to_string_lossy()
+        .replace(&process::id().to_string(), "999999999");
+      let ours = temp_sibling(&real_path);
+      for dir in &[Path::new(&stale), &ours] {
+        fs::create_dir_all(dir.join("sub")).await?;
+        fs::set_permissions(dir, std::os::unix::fs::PermissionsExt::from_mode(0o555)).await?;
+      }
+      let src = temp.path().join("src");
+      fs::write(&src, "foo").await?;
+      let lookalike = add_file(&store, &src, "foo.tmp-999999999-0").await?;
+      drop(store);
+      let store = LocalStore::open(temp.path())?;
+      assert!(fs::symlink_metadata(&stale).await.is_err());
+      assert!(fs::symlink_metadata(&ours).await.is_ok());
+      assert!(fs::symlink_metadata(store.to_real_path(&lookalike))
+        .await
+        .is_ok());
+
+      Ok(())
+    })
+  }
+
+  #[test]
+  fn repair() -> anyhow::Result<()> {
+    crate::util::run_test(async {
+      let store = get_local_store()?;
+      let src = Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/Cargo.toml"));
+      let add = |repair| {
+        store.add_path_to_store(
+          "Cargo.toml",
+          src,
+          HashType::SHA256,
+          PathFilter::always(),
+          repair,
+        )
+      };
+      let path = add(false).await?;
+      let real_path = store.print_store_path(&path);
+      let original = fs::read(&real_path).await?;
+
+      fs::write(&real_path, "corrupt").await?;
+      add(false).await?;
+      assert_eq!(fs::read(&real_path).await?, b"corrupt");
+      add(true).await?;
+      assert_eq!(fs::read(&real_path).await?, original);
+
+      let info = store.db.get_path_info(&path).await?.unwrap();
+      let mut nar = ArchiveSink::new(vec![]);
+      store.nar_from_path(&path, &mut nar).await?;
+      let nar = nar.into_inner();
+      fs::write(&real_path, "corrupt").await?;
+      store
+        .add_nar_to_store(&info, futures::stream::iter(nar.into_iter().map(Ok)), true)
+        .await?;
+      assert_eq!(fs::read(&real_path).await?, original);
+
+      // a bad replacement leaves the path as it was
+      fs::write(&real_path, "corrupt").await?;
+      let bad = futures::stream::iter(Some(Ok(Bytes::from_static(b"garbage"))));
+      assert!(store.add_nar_to_store(&info, bad, true).await.is_err());
+      assert_eq!(fs::read(&real_path).await?, b"corrupt");
+      assert!(store.is_valid_path(&path).await?);
+
+      Ok(())
+    })
+  }
+
+  #[test]
+  fn fixed_output_mismatch() -> anyhow::Result<()> {
+    crate::util::run_test(async {
+      let store = get_local_store()?;
+      let path = Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/Cargo.toml"));
+      let (good, _) = Hash::hash_file(path, HashType::SHA256).await?;
+      let (expected, _) = store
+        .store_path_for_file("Cargo.toml", path, HashType::SHA256)
+        .await?;
+      assert_eq!(
+        store
+          .check_fixed_output("Cargo.toml", path, false, &good)
+          .await?,
+        expected
+      );
+
+      let bad = Hash::hash_str("", HashType::SHA256);
+      let err = store
+        .check_fixed_output("Cargo.toml", path, false, &bad)
+        .await
+        .unwrap_err();
+      assert_matches::assert_matches!(
+        err.downcast_ref::<crate::store::error::Error>(),
+        Some(crate::store::error::Error::FixedOutputHashMismatch { actual, .. }) if *actual == good
+      );
+
+      Ok(())
+    })
+  }
+
   #[test]
   fn add_nar() -> anyhow::Result<()> {
     crate::util::run_test(async {
@@ -502,10 +1034,154 @@ mod tests {
             ultimate: false,
           },
           nar_stream,
+          false,
         )
         .await?;
 
       Ok(())
     })
   }
+
+  #[test]
+  fn read_only() -> anyhow::Result<()> {
+    crate::util::run_test(async {
+      let temp = tempfile::tempdir()?;
+      let src = temp.path().join("src");
+      fs::write(&src, "foo").await?;
+      let path = LocalStore::open(temp.path())?
+        .add_path_to_store("foo", &src, HashType::SHA256, PathFilter::always(), false)
+        .await?;
+      let dirs = Dirs::new(temp.path());
+      std::fs::remove_dir_all(dirs.temproots_dir())?;
+
+      let is_read_only =
+        |e: anyhow::Error| matches!(e.downcast_ref(), Some(Error::ReadOnly { .. }));
+      for immutable in &[false, true] {
+        let config = DbConfig {
+          immutable: *immutable,
+          ..Default::default()
+        };
+        let store = LocalStore::open_read_only_with(Dirs::new(temp.path()), &config)?;
+        assert!(store.is_valid_path(&path).await?);
+        assert_eq!(store.db.get_valid_paths().await?.len(), 1);
+
+        assert!(is_read_only(
+          store
+            .add_path_to_store("bar", &src, HashType::SHA256, PathFilter::always(), false)
+            .await
+            .unwrap_err()
+        ));
+        assert!(is_read_only(store.add_temp_root(&path).await.unwrap_err()));
+        assert!(is_read_only(
+          store
+            .collect_garbage(&Default::default())
+            .await
+            .unwrap_err()
+        ));
+        assert!(is_read_only(store.optimise_store().await.unwrap_err()));
+        // and SQLite won't let anything through either
+        assert!(store.db.invalidate_paths(vec![path.clone()]).await.is_err());
+      }
+      assert!(!dirs.temproots_dir().exists());
+
+      Ok(())
+    })
+  }
+
+  async fn add_file(store: &LocalStore, src: &Path, name: &str) -> anyhow::Result<StorePath> {
+    store
+      .add_path_to_store(name, src, HashType::SHA256, PathFilter::always(), false)
+      .await
+  }
+
+  #[test]
+  fn concurrent_imports() -> anyhow::Result<()> {
+    crate::util::run_test(async {
+      let temp = tempfile::tempdir()?;
+      let store = LocalStore::open(temp.path())?;
+      // each import opens the lock file afresh, so they lock each other out
+      // just like separate processes would
+      let src = Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/src/archive"));
+      let paths =
+        futures::future::try_join_all((0..4).map(|_| add_file(&store, src, "archive"))).await?;
+      assert!(paths.iter().all(|p| *p == paths[0]));
+      assert!(store.is_valid_path(&paths[0]).await?);
+
+      Ok(())
+    })
+  }
+
+  #[test]
+  fn lock_timeout() -> anyhow::Result<()> {
+    crate::util::run_test(async {
+      let temp = tempfile::tempdir()?;
+      let src = temp.path().join("src");
+      fs::write(&src, "foo").await?;
+      let open = |d| LocalStore::open_with(Dirs::chroot(temp.path().join(d)), &Default::default());
+      let path = add_file(&open("a")?, &src, "foo").await?;
+
+      // store paths are named after the store dir, so `path' is the one
+      // that importing `src' into another chroot gives too
+      let mut store = open("b")?;
+      store.set_lock_timeout(Some(Duration::from_millis(50)));
+      // somebody else is busy importing it
+      let mut held = PathLocks::new();
+      assert!(
+        held
+          .lock(Some(store.to_real_path(&path)), false, None)
+          .await?
+      );
+      let err = add_file(&store, &src, "foo").await.unwrap_err();
+      assert_matches::assert_matches!(err.downcast_ref::<Error>(), Some(Error::LockTimeout { .. }));
+      assert!(!store.is_valid_path(&path).await?);
+
+      held.unlock();
+      assert_eq!(add_file(&store, &src, "foo").await?, path);
+
+      Ok(())
+    })
+  }
+
+  #[test]
+  fn chroot() -> anyhow::Result<()> {
+    crate::util::run_test(async {
+      let temp = tempfile::tempdir()?;
+      let src = temp.path().join("src");
+      fs::write(&src, "foo").await?;
+
+      let store = LocalStore::open_with(Dirs::chroot(temp.path().join("a")), &Default::default())?;
+      let other = LocalStore::open_with(Dirs::chroot(temp.path().join("b")), &Default::default())?;
+      let rooted = add_file(&store, &src, "rooted").await?;
+      let dead = add_file(&store, &src, "dead").await?;
+
+      // paths are named and hashed after the store dir, wherever it is
+      assert_eq!(add_file(&other, &src, "rooted").await?, rooted);
+      assert_ne!(add_file(&get_local_store()?, &src, "rooted").await?, rooted);
+      assert!(store.print_store_path(&rooted).starts_with("/nix/store/"));
+      let real_path = store.to_real_path(&rooted);
+      assert!(real_path.starts_with(temp.path().join("a/nix/store")));
+      assert_eq!(fs::read(&real_path).await?, b"foo");
+      assert!(temp.path().join("a/nix/var/nix/db/db.sqlite").exists());
+
+      let problems = store
+        .verify_store(true, None::<&LocalStore>)
+        .await?
+        .problems;
+      assert!(problems.is_empty(), "{:?}", problems);
+
+      // roots point at where the path says it is
+      drop(store);
+      let store = LocalStore::open_with(Dirs::chroot(temp.path().join("a")), &Default::default())?;
+      std::os::unix::fs::symlink(
+        store.print_store_path(&rooted),
+        store.dirs.gcroots_dir().join("root"),
+      )?;
+      let results = store.collect_garbage(&Default::default()).await?;
+      assert!(results.paths.contains(&store.to_real_path(&dead)));
+      assert!(real_path.exists());
+      assert!(!store.to_real_path(&dead).exists());
+
+      Ok(())
+    })
+  }
 }
@@ -0,0 +1,269 @@
+use super::LocalStore;
+use crate::{
+  archive::{ArchiveSink, PathFilter},
+  hash::{Encoding, HashType},
+  prelude::*,
+  Store,
+};
+use std::{
+  collections::HashSet,
+  io,
+  os::unix::fs::{MetadataExt, PermissionsExt},
+  path::Path,
+  process,
+  sync::atomic::{AtomicU64, Ordering},
+};
+use tokio::fs;
+
+static TEMP_LINK_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Default, Debug)]
+pub struct OptimiseStats {
+  pub files_linked: u64,
+  /// The size of the files whose last other copy was replaced by a link.
+  pub bytes_freed: u64,
+}
+
+impl LocalStore {
+  /// Replace identical files throughout the store with hard links to a
+  /// single copy in the `.links` directory.
+  pub async fn optimise_store(&self) -> Result<OptimiseStats> {
+    self.check_writable()?;
+    let mut stats = OptimiseStats::default();
+    let mut inodes = self.linked_inodes().await?;
+    let paths = self.db.get_valid_paths().await?;
+    for path in paths {
+      self.add_temp_root(&path).await?;
+      // it may have been garbage collected in the meantime
+      if !self.is_valid_path(&path).await? {
+        continue;
+      }
+      let real_path = self.to_real_path(&path);
+      self
+        .optimise_path_impl(&real_path, &mut stats, &mut inodes)
+        .await?;
+    }
+    info!(
+      "{} files linked, {} bytes freed",
+      stats.files_linked, stats.bytes_freed
+    );
+    Ok(stats)
+  }
+
+  /// Deduplicate the files of a freshly added path.
+  pub(super) async fn optimise_path(&self, path: &Path) -> Result<OptimiseStats> {
+    let mut stats = OptimiseStats::default();
+    self
+      .optimise_path_impl(path, &mut stats, &mut HashSet::new())
+      .await?;
+    Ok(stats)
+  }
+
+  /// The inodes that already have a copy in `.links`, so that we needn't
+  /// hash them again.
+  async fn linked_inodes(&self) -> Result<HashSet<u64>> {
+    let mut inodes = HashSet::new();
+    let mut entries = match fs::read_dir(self.dirs.links_dir()).await {
+      Ok(e) => e,
+      Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(inodes),
+      Err(e) => return Err(e.into()),
+    };
+    while let Some(entry) = entries.next_entry().await? {
+      inodes.insert(fs::symlink_metadata(entry.path()).await?.ino());
+    }
+    Ok(inodes)
+  }
+
+  #[async_recursion]
+  async fn optimise_path_impl(
+    &self,
+    path: &Path,
+    stats: &mut OptimiseStats,
+    inodes: &mut HashSet<u64>,
+  ) -> Result<()> {
+    let meta = fs::symlink_metadata(path).await?;
+    if meta.is_dir() {
+      let mut entries = fs::read_dir(path).await?;
+      while let Some(entry) = entries.next_entry().await? {
+        self
+          .optimise_path_impl(&entry.path(), stats, inodes)
+          .await?;
+      }
+      return Ok(());
+    }
+    if !meta.is_file() || inodes.contains(&meta.ino()) {
+      return Ok(());
+    }
+
+    let mut sink = ArchiveSink::new(crate::hash::Sink::new(HashType::SHA256));
+    crate::archive::dump_path(path, &mut sink, &PathFilter::always()).await?;
+    let hash = sink.into_inner().finish().0.encode(Encoding::Base32);
+
+    // the first copy of a file becomes the one everything else links to
+    fs::create_dir_all(self.dirs.links_dir()).await?;
+    let link = self.dirs.links_dir().join(&hash);
+    match fs::hard_link(path, &link).await {
+      Ok(()) => {
+        inodes.insert(meta.ino());
+        return Ok(());
+      }
+      Err(e) if e.raw_os_error() == Some(libc::ENOSPC) => {
+        info!(
+          "cannot link `{}' to `{}': the `.links' directory is full",
+          link.display(),
+          path.display()
+        );
+        return Ok(());
+      }
+      Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+      Err(e) => {
+        return Err(e)
+          .with_context(|| format!("while linking `{}' to `{}'", link.display(), path.display()))
+      }
+    }
+
+    let link_meta = fs::symlink_metadata(&link).await?;
+    if link_meta.ino() == meta.ino() {
+      debug!(
+        "`{}' is already linked to `{}'",
+        path.display(),
+        link.display()
+      );
+      inodes.insert(meta.ino());
+      return Ok(());
+    }
+
+    debug!("linking `{}' to `{}'", path.display(), link.display());
+    let parent = path.parent().unwrap_or(path);
+    let parent_mode = fs::metadata(parent).await?.permissions().mode();
+    if parent_mode & 0o200 == 0 {
+      fs::set_permissions(parent, std::fs::Permissions::from_mode(parent_mode | 0o200)).await?;
+    }
+    let linked = self.replace_with_link(path, &link).await;
+    if parent_mode & 0o200 == 0 {
+      fs::set_permissions(parent, std::fs::Permissions::from_mode(parent_mode)).await?;
+    }
+
+    if linked? {
+      stats.files_linked += 1;
+      if meta.nlink() == 1 {
+        stats.bytes_freed += meta.len();
+      }
+    }
+    Ok(())
+  }
+
+  /// Atomically replace `path` with a hard link to `link`. Returns whether
+  /// that was possible.
+  async fn replace_with_link(&self, path: &Path, link: &Path) -> Result<bool> {
+    let temp = self.dirs.real_store_dir().join(format!(
+      ".tmp-link-{}-{}",
+      process::id(),
+      TEMP_LINK_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    match fs::hard_link(link, &temp).await {
+      Ok(()) => {}
+      Err(e) if e.raw_os_error() == Some(libc::EMLINK) => {
+        info!("`{}' has the maximum number of links", link.display());
+        return Ok(false);
+      }
+      Err(e) if e.raw_os_error() == Some(libc::ENOSPC) => {
+        info!(
+          "cannot link `{}' to `{}': no space left",
+          temp.display(),
+          link.display()
+        );
+        return Ok(false);
+      }
+      Err(e) => {
+        return Err(e)
+          .with_context(|| format!("while linking `{}' to `{}'", temp.display(), link.display()))
+      }
+    }
+
+    if let Err(e) = fs::rename(&temp, path).await {
+      let _ = fs::remove_file(&temp).await;
+      return Err(e).with_context(|| {
+        format!(
+          "while renaming `{}' to `{}'",
+          temp.display(),
+          path.display()
+        )
+      });
+    }
+    Ok(true)
+  }
+
+  /// Delete the files in `.links` that nothing else links to any more.
+  /// Returns the number of bytes freed.
+  pub(super) async fn remove_unused_links(&self) -> Result<u64> {
+    let mut freed = 0;
+    let mut entries = match fs::read_dir(self.dirs.links_dir()).await {
+      Ok(e) => e,
+      Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+      Err(e) => return Err(e.into()),
+    };
+    while let Some(entry) = entries.next_entry().await? {
+      let meta = fs::symlink_metadata(entry.path()).await?;
+      if meta.nlink() != 1 {
+        continue;
+      }
+      debug!("deleting unused link `{}'", entry.path().display());
+      fs::remove_file(entry.path()).await?;
+      freed += meta.blocks() * 512;
+    }
+    Ok(freed)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  async fn add(store: &LocalStore, src: &Path, name: &str) -> Result<PathBuf> {
+    let path = store
+      .add_path_to_store(name, src, HashType::SHA256, PathFilter::always(), false)
+      .await?;
+    Ok(store.store_path().join(path.to_string()))
+  }
+
+  #[test]
+  fn optimise() -> Result<()> {
+    crate::util::run_test(async {
+      let temp = tempfile::tempdir()?;
+      let mut store = LocalStore::open(temp.path())?;
+      let src = temp.path().join("src");
+      fs::write(&src, "same contents").await?;
+      let a = add(&store, &src, "a").await?;
+      let b = add(&store, &src, "b").await?;
+
+      let stats = store.optimise_store().await?;
+      assert_eq!(stats.files_linked, 1);
+      assert_eq!(stats.bytes_freed, "same contents".len() as u64);
+      let ino = fs::metadata(&a).await?.ino();
+      assert_eq!(fs::metadata(&b).await?.ino(), ino);
+      assert_eq!(fs::metadata(&a).await?.nlink(), 3);
+      assert_eq!(fs::read(&b).await?, b"same contents");
+      assert_eq!(store.optimise_store().await?.files_linked, 0);
+
+      store.set_auto_optimise(true);
+      let c = add(&store, &src, "c").await?;
+      assert_eq!(fs::metadata(&c).await?.ino(), ino);
+
+      // once nothing else uses it, the copy in `.links' is garbage too; the
+      // temporary roots go away with the store that made them
+      drop(store);
+      let store = LocalStore::open(temp.path())?;
+      let results = store.collect_garbage(&Default::default()).await?;
+      assert!([&a, &b, &c].iter().all(|p| results.paths.contains(*p)));
+      assert!(results.bytes_freed > 0);
+      assert!(fs::read_dir(store.dirs.links_dir())
+        .await?
+        .next_entry()
+        .await?
+        .is_none());
+
+      Ok(())
+    })
+  }
+}
@@ -0,0 +1,295 @@
+use super::{gc, LocalStore};
+use crate::{
+  archive::{ArchiveSink, PathFilter},
+  hash::Hash,
+  path::{Path as StorePath, PathSet},
+  path_info::ValidPathInfo,
+  prelude::*,
+  Store,
+};
+use tokio::fs;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Problem {
+  /// A registered path doesn't exist in the store directory.
+  Missing(StorePath),
+  /// An entry in the store directory isn't a registered path.
+  Unregistered(PathBuf),
+  /// A registered path refers to a path that isn't valid.
+  InvalidReference {
+    path: StorePath,
+    reference: StorePath,
+  },
+  /// The contents of a registered path don't match its NAR hash or size.
+  Corrupt {
+    path: StorePath,
+    expected_hash: Hash,
+    actual_hash: Hash,
+    expected_size: Option<u64>,
+    actual_size: u64,
+  },
+}
+
+#[derive(Debug, Default)]
+pub struct VerifyResults {
+  /// Everything that was wrong, including what has since been repaired.
+  pub problems: Vec<Problem>,
+  pub repaired: PathSet,
+}
+
+impl LocalStore {
+  /// Check that the database and the store directory agree with each other,
+  /// and if `check_contents` is set, that every path still has the contents
+  /// it was registered with. Missing and corrupt paths are fetched again
+  /// from `repair`, if given.
+  pub async fn verify_store<S: Store>(
+    &self,
+    check_contents: bool,
+    repair: Option<&S>,
+  ) -> Result<VerifyResults> {
+    if repair.is_some() {
+      self.check_writable()?;
+    }
+    let mut results = VerifyResults::default();
+    let valid = self.db.get_valid_paths().await?;
+
+    let mut entries = fs::read_dir(self.dirs.real_store_dir()).await?;
+    while let Some(entry) = entries.next_entry().await? {
+      let name = entry.file_name();
+      let name = name.to_string_lossy();
+      // leave alone what's there for our own purposes, and what belongs to
+      // paths that are being worked on
+      if name == ".links"
+        || name == "trash"
+        || name.starts_with(".tmp-link-")
+        || gc::TEMP_SUFFIXES.iter().any(|s| name.ends_with(s))
+        || super::parse_temp_sibling(&name).is_some()
+      {
+        continue;
+      }
+      match StorePath::from_base_name(&name) {
+        Ok(p) if valid.contains(&p) => {}
+        _ => results.problems.push(Problem::Unregistered(entry.path())),
+      }
+    }
+
+    for path in &valid {
+      let info = match self.db.get_path_info(path).await? {
+        Some(info) => info,
+        None => continue,
+      };
+      for reference in &info.references {
+        if !self.is_valid_path(reference).await? {
+          results.problems.push(Problem::InvalidReference {
+            path: path.clone(),
+            reference: reference.clone(),
+          });
+        }
+      }
+
+      let real_path = self.to_real_path(path);
+      let problem = if fs::symlink_metadata(&real_path).await.is_err() {
+        Problem::Missing(path.clone())
+      } else if check_contents {
+        debug!("checking contents of `{}'", real_path.display());
+        let mut sink = ArchiveSink::new(crate::hash::Sink::new(info.nar_hash.type_()));
+        crate::archive::dump_path(&real_path, &mut sink, &PathFilter::always()).await?;
+        let (actual_hash, actual_size) = sink.into_inner().finish();
+        let actual_size = actual_size as u64;
+        let size_ok = info.nar_size.is_none_or(|s| s == 0 || s == actual_size);
+        if actual_hash == info.nar_hash && size_ok {
+          continue;
+        }
+        Problem::Corrupt {
+          path: path.clone(),
+          expected_hash: info.nar_hash.clone(),
+          actual_hash,
+          expected_size: info.nar_size,
+          actual_size,
+        }
+      } else {
+        continue;
+      };
+      results.problems.push(problem);
+
+      if let Some(source) = repair {
+        match self.repair_path(source, &info).await {
+          Ok(()) => {
+            results.repaired.insert(path.clone());
+          }
+          Err(e) => warn!("cannot repair path `{}': {:#}", real_path.display(), e),
+        }
+      }
+    }
+
+    Ok(results)
+  }
+
+  /// Replace the contents of a valid path with a fresh copy from `source`.
+  async fn repair_path<S: Store>(&self, source: &S, info: &ValidPathInfo) -> Result<()> {
+    if !source.is_valid_path(&info.store_path).await? {
+      bail!("path is not valid in `{}'", source.get_uri());
+    }
+    let mut nar = ArchiveSink::new(vec![]);
+    source.nar_from_path(&info.store_path, &mut nar).await?;
+    let nar = nar.into_inner();
+
+    info!(
+      "repairing path `{}'",
+      self.print_store_path(&info.store_path)
+    );
+    self
+      .add_nar_to_store(info, futures::stream::iter(nar.into_iter().map(Ok)), true)
+      .await
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{hash::HashType, path_info::PathInfo, store::ByteStream};
+  use std::{
+    borrow::Cow,
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+  };
+
+  /// A store that has copies of some paths of another store in a separate
+  /// directory.
+  struct Backup {
+    store_dir: PathBuf,
+    dir: PathBuf,
+    infos: BTreeMap<StorePath, ValidPathInfo>,
+  }
+
+  #[async_trait]
+  impl Store for Backup {
+    fn store_path(&self) -> Cow<'_, Path> {
+      Cow::Borrowed(&self.store_dir)
+    }
+
+    fn get_uri(&self) -> String {
+      "backup".into()
+    }
+
+    async fn get_path_info(&self, path: &StorePath) -> Result<Option<Arc<dyn PathInfo>>> {
+      Ok(
+        self
+          .infos
+          .get(path)
+          .map(|i| Arc::new(i.clone()) as Arc<dyn PathInfo>),
+      )
+    }
+
+    async fn get_referrers(&self, _: &StorePath) -> Result<PathSet> {
+      Ok(PathSet::new())
+    }
+
+    async fn nar_from_path<W: Sink<Bytes> + Send + Unpin>(
+      &self,
+      path: &StorePath,
+      sink: &mut ArchiveSink<W>,
+    ) -> Result<()>
+    where
+      W::Error: std::error::Error + Send + Sync + 'static,
+    {
+      crate::archive::dump_path(
+        &self.dir.join(path.to_string()),
+        sink,
+        &PathFilter::always(),
+      )
+      .await
+    }
+
+    async fn add_nar_to_store<S: ByteStream + Send + Unpin>(
+      &self,
+      _: &ValidPathInfo,
+      _: S,
+      _: bool,
+    ) -> Result<()> {
+      bail!("read-only")
+    }
+
+    async fn add_path_to_store(
+      &self,
+      _: &str,
+      _: &Path,
+      _: HashType,
+      _: PathFilter,
+      _: bool,
+    ) -> Result<StorePath> {
+      bail!("read-only")
+    }
+
+    async fn add_temp_root(&self, _: &StorePath) -> Result<()> {
+      Ok(())
+    }
+  }
+
+  #[test]
+  fn verify() -> Result<()> {
+    crate::util::run_test(async {
+      let temp = tempfile::tempdir()?;
+      let store = LocalStore::open(temp.path())?;
+      let mut backup = Backup {
+        store_dir: store.store_path().into(),
+        dir: temp.path().join("backup"),
+        infos: BTreeMap::new(),
+      };
+      fs::create_dir(&backup.dir).await?;
+
+      let mut paths = vec![];
+      for name in &["ok", "corrupt", "missing"] {
+        let src = temp.path().join(name);
+        fs::write(&src, name).await?;
+        let path = store
+          .add_path_to_store(name, &src, HashType::SHA256, PathFilter::always(), false)
+          .await?;
+        fs::copy(&src, backup.dir.join(path.to_string())).await?;
+        let info = store.db.get_path_info(&path).await?.unwrap();
+        backup.infos.insert(path.clone(), info);
+        paths.push(path);
+      }
+      let real = |p: &StorePath| store.store_path().join(p.to_string());
+      fs::write(real(&paths[1]), "CORRUPT").await?;
+      fs::remove_file(real(&paths[2])).await?;
+      fs::write(store.store_path().join("junk"), "").await?;
+      for suffix in &[".check", ".old", ".tmp-1-0"] {
+        let name = format!("{}{}", paths[0], suffix);
+        fs::write(store.store_path().join(name), "").await?;
+      }
+
+      let results = store.verify_store(false, None::<&Backup>).await?;
+      assert_eq!(
+        results.problems,
+        vec![
+          Problem::Unregistered(store.store_path().join("junk")),
+          Problem::Missing(paths[2].clone())
+        ]
+      );
+
+      let results = store.verify_store(true, Some(&backup)).await?;
+      assert_eq!(results.problems.len(), 3);
+      assert!(results.problems.iter().any(|p| match p {
+        Problem::Corrupt {
+          path, actual_size, ..
+        } => *path == paths[1] && *actual_size != 0,
+        _ => false,
+      }));
+      assert_eq!(
+        results.repaired,
+        paths[1..].iter().cloned().collect::<PathSet>()
+      );
+      assert_eq!(fs::read(real(&paths[1])).await?, b"corrupt");
+
+      let results = store.verify_store(true, None::<&Backup>).await?;
+      assert_eq!(
+        results.problems,
+        vec![Problem::Unregistered(store.store_path().join("junk"))]
+      );
+
+      Ok(())
+    })
+  }
+}
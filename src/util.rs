@@ -42,3 +42,57 @@ pub fn run_test<F: futures::Future<Output = anyhow::Result<()>>>(test: F) -> any
   }
   tokio::runtime::Runtime::new().unwrap().block_on(test)
 }
+
+/// A derivation with a single `out` output at `out` and the environment
+/// `env`, for tests to fill in further.
+#[cfg(test)]
+pub fn test_derivation(
+  out: &crate::path::Path,
+  env: &[(&str, &str)],
+) -> crate::derivation::Derivation {
+  use crate::derivation::{Derivation, DerivationOutput};
+  Derivation {
+    outputs: Some((
+      "out".to_string(),
+      DerivationOutput {
+        path: out.clone(),
+        hash_algo: String::new(),
+        hash: String::new(),
+      },
+    ))
+    .into_iter()
+    .collect(),
+    input_drvs: Default::default(),
+    input_srcs: Default::default(),
+    platform: "x86_64-linux".into(),
+    builder: "/bin/sh".into(),
+    args: vec![],
+    env: env
+      .iter()
+      .map(|(k, v)| (k.to_string(), v.to_string()))
+      .collect(),
+  }
+}
+
+/// Information about `store_path`, whose serialisation is `nar`, for tests
+/// to register.
+#[cfg(test)]
+pub fn test_path_info(
+  store_path: &crate::path::Path,
+  nar: &[u8],
+  references: &[&crate::path::Path],
+) -> crate::path_info::ValidPathInfo {
+  use crate::hash::{Hash, HashType};
+  crate::path_info::ValidPathInfo {
+    store_path: store_path.clone(),
+    deriver: None,
+    nar_hash: Hash::hash_bytes(nar, HashType::SHA256),
+    references: references.iter().map(|r| (*r).clone()).collect(),
+    registration_time: std::time::SystemTime::now(),
+    nar_size: Some(nar.len() as u64),
+    id: 0,
+    signatures: Default::default(),
+    content_addressed: None,
+    ultimate: true,
+  }
+}